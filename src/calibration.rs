@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::camera::{Distortion, Intrinsics, ProjectionModel};
+
+/// OpenCV and ROS place the center of the top left pixel at (0, 0), this crate at (0.5, 0.5)
+const PIXEL_CENTER_OFFSET_PX: f32 = 0.5;
+
+/// File format of a camera calibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CalibrationFormat {
+    /// Intrinsics as toml, see Intrinsics::load
+    Toml,
+    /// OpenCV FileStorage YAML or ROS camera_info
+    Opencv,
+    /// Kalibr camchain.yaml
+    Kalibr,
+    /// COLMAP cameras.txt
+    Colmap,
+}
+
+impl CalibrationFormat {
+    /// Detect the format from the file extension, and Kalibr camchains by their camera_model keys
+    pub fn detect(path: &Path) -> Result<Self> {
+        Ok(match path.extension().and_then(OsStr::to_str) {
+            Some("toml") => Self::Toml,
+            Some("txt") => Self::Colmap,
+            Some("yaml" | "yml") => {
+                if std::fs::read_to_string(path)?.contains("camera_model") {
+                    Self::Kalibr
+                } else {
+                    Self::Opencv
+                }
+            }
+            _ => bail!(
+                "Unable to detect the calibration format of {}",
+                path.display()
+            ),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CalibrationConfig {
+    /// Path to the camera calibration, a toml of the intrinsics, an OpenCV or ROS yaml, a Kalibr
+    /// camchain.yaml or a COLMAP cameras.txt
+    #[clap(long, default_value = "camera_params.toml")]
+    pub calibration_path: PathBuf,
+    /// Format of the calibration, detected from the file if not given
+    #[clap(long, value_enum)]
+    pub calibration_format: Option<CalibrationFormat>,
+    /// Camera to use from a Kalibr camchain (cam0 by default) or COLMAP camera id (1 by default)
+    #[clap(long)]
+    pub calibration_camera: Option<String>,
+    /// Render at this width instead of the calibrated one, scaling the focal lengths and optical
+    /// center, multiple of 64
+    #[clap(long)]
+    pub render_width_px: Option<u32>,
+    /// Render at this height instead of the calibrated one, by default the calibrated aspect
+    /// ratio is kept when only the width is given
+    #[clap(long)]
+    pub render_height_px: Option<u32>,
+}
+
+impl CalibrationConfig {
+    /// Load the calibration and scale it to the render size
+    pub fn load(&self) -> Result<Intrinsics> {
+        let intrinsics = Intrinsics::load_calibration(
+            &self.calibration_path,
+            self.calibration_format,
+            self.calibration_camera.as_deref(),
+        )?;
+        let aspect_ratio = intrinsics.image_height_px as f32 / intrinsics.image_width_px as f32;
+        Ok(match (self.render_width_px, self.render_height_px) {
+            (None, None) => intrinsics,
+            (Some(width_px), Some(height_px)) => intrinsics.scaled(width_px, height_px),
+            (Some(width_px), None) => {
+                let height_px = (width_px as f32 * aspect_ratio).round() as u32;
+                intrinsics.scaled(width_px, height_px)
+            }
+            (None, Some(height_px)) => {
+                let width_px = (height_px as f32 / aspect_ratio).round() as u32;
+                intrinsics.scaled(width_px, height_px)
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenCvMatrix {
+    data: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OpenCvValue {
+    Scalar(f32),
+    Matrix(OpenCvMatrix),
+}
+
+/// Calibration as written by OpenCV FileStorage or the ROS camera_calibration package
+#[derive(Deserialize)]
+struct OpenCvCalibration {
+    image_width: u32,
+    image_height: u32,
+    #[serde(alias = "K")]
+    camera_matrix: OpenCvMatrix,
+    #[serde(default, alias = "D", alias = "dist_coeffs")]
+    distortion_coefficients: Option<OpenCvMatrix>,
+    #[serde(default)]
+    distortion_model: Option<String>,
+    /// Only present for the omnidirectional (unified) model of cv::omnidir
+    #[serde(default)]
+    xi: Option<OpenCvValue>,
+}
+
+/// One camera of a Kalibr camchain
+#[derive(Deserialize)]
+struct KalibrCamera {
+    camera_model: String,
+    intrinsics: Vec<f32>,
+    #[serde(default)]
+    distortion_model: Option<String>,
+    #[serde(default)]
+    distortion_coeffs: Vec<f32>,
+    resolution: [u32; 2],
+}
+
+/// Radial-tangential distortion from up to five coefficients k1, k2, p1, p2, k3
+fn radtan(coefficients: &[f32]) -> Result<Option<Distortion>> {
+    ensure!(
+        coefficients.len() <= 5,
+        "Only k1, k2, p1, p2, k3 distortion coefficients are supported, got {}",
+        coefficients.len()
+    );
+    if coefficients.iter().all(|c| *c == 0.0) {
+        return Ok(None);
+    }
+    let coefficient = |i: usize| coefficients.get(i).copied().unwrap_or(0.0);
+    Ok(Some(Distortion {
+        k1: coefficient(0),
+        k2: coefficient(1),
+        p1: coefficient(2),
+        p2: coefficient(3),
+        k3: coefficient(4),
+    }))
+}
+
+/// Kannala-Brandt model from up to four coefficients k1..k4
+fn kannala_brandt(coefficients: &[f32]) -> Result<ProjectionModel> {
+    ensure!(
+        coefficients.len() <= 4,
+        "Equidistant model takes at most 4 coefficients, got {}",
+        coefficients.len()
+    );
+    let coefficient = |i: usize| coefficients.get(i).copied().unwrap_or(0.0);
+    Ok(ProjectionModel::KannalaBrandt {
+        k1: coefficient(0),
+        k2: coefficient(1),
+        k3: coefficient(2),
+        k4: coefficient(3),
+    })
+}
+
+impl Intrinsics {
+    /// Load a calibration of the given or detected format
+    ///
+    /// The camera selects one camera of Kalibr camchains and COLMAP cameras.txt files, cam0 and
+    /// camera 1 by default, and is ignored for the other formats.
+    pub fn load_calibration<P: AsRef<Path>>(
+        path: P,
+        format: Option<CalibrationFormat>,
+        camera: Option<&str>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let format = match format {
+            Some(format) => format,
+            None => CalibrationFormat::detect(path)?,
+        };
+        match format {
+            CalibrationFormat::Toml => Self::load(path),
+            CalibrationFormat::Opencv => Self::load_opencv_yaml(path),
+            CalibrationFormat::Kalibr => Self::load_kalibr(path, camera.unwrap_or("cam0")),
+            CalibrationFormat::Colmap => {
+                let camera_id = camera
+                    .map(str::parse)
+                    .transpose()
+                    .context("COLMAP camera ids are numbers")?;
+                Self::load_colmap(path, camera_id.unwrap_or(1))
+            }
+        }
+    }
+
+    /// Load an OpenCV FileStorage YAML or ROS camera_info calibration
+    ///
+    /// The model is taken from `distortion_model` if present ("plumb_bob"/"radtan" or
+    /// "equidistant"/"fisheye"), otherwise a `xi` entry selects the cv::omnidir unified model
+    /// and everything else is treated as pinhole with radial-tangential distortion.
+    pub fn load_opencv_yaml<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_opencv_yaml(&std::fs::read_to_string(path)?)
+    }
+
+    fn from_opencv_yaml(content: &str) -> Result<Self> {
+        // Strip the non standard "%YAML:1.0" directive and matrix type tags
+        let content = content
+            .lines()
+            .filter(|line| !line.starts_with('%'))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .replace("!!opencv-matrix", "");
+        let calibration: OpenCvCalibration = serde_yaml::from_str(&content)?;
+
+        let k = &calibration.camera_matrix.data;
+        ensure!(
+            k.len() == 9,
+            "Camera matrix needs 9 entries, got {}",
+            k.len()
+        );
+        let coefficients = calibration
+            .distortion_coefficients
+            .map(|d| d.data)
+            .unwrap_or_default();
+        let xi = calibration.xi.map(|xi| match xi {
+            OpenCvValue::Scalar(xi) => Ok(xi),
+            OpenCvValue::Matrix(matrix) => matrix.data.first().copied().context("Empty xi"),
+        });
+
+        let (model, distortion) = match (calibration.distortion_model.as_deref(), xi) {
+            (Some("equidistant" | "fisheye" | "kannala_brandt"), _) => {
+                (kannala_brandt(&coefficients)?, None)
+            }
+            (Some("plumb_bob" | "radtan"), None) | (None, None) => {
+                (ProjectionModel::Pinhole, radtan(&coefficients)?)
+            }
+            (Some("plumb_bob" | "radtan") | None, Some(xi)) => {
+                (ProjectionModel::Unified { xi: xi? }, radtan(&coefficients)?)
+            }
+            (Some(distortion_model), _) => {
+                bail!("Unsupported distortion model {}", distortion_model)
+            }
+        };
+
+        Ok(Self {
+            model,
+            distortion,
+            focal_length_x_px: k[0],
+            focal_length_y_px: k[4],
+            optical_center_x_px: k[2] + PIXEL_CENTER_OFFSET_PX,
+            optical_center_y_px: k[5] + PIXEL_CENTER_OFFSET_PX,
+            image_width_px: calibration.image_width,
+            image_height_px: calibration.image_height,
+        })
+    }
+
+    /// Load one camera (e.g. "cam0") of a Kalibr camchain.yaml
+    ///
+    /// Supports the pinhole, omni and ds camera models with radtan, equidistant or no distortion.
+    pub fn load_kalibr<P: AsRef<Path>>(path: P, camera_name: &str) -> Result<Self> {
+        Self::from_kalibr(&std::fs::read_to_string(path)?, camera_name)
+    }
+
+    fn from_kalibr(content: &str, camera_name: &str) -> Result<Self> {
+        let mut camchain: HashMap<String, KalibrCamera> = serde_yaml::from_str(content)?;
+        let camera = camchain
+            .remove(camera_name)
+            .with_context(|| format!("Camera {} not found in camchain", camera_name))?;
+
+        let (model, focal_params) = match camera.camera_model.as_str() {
+            "pinhole" => (ProjectionModel::Pinhole, &camera.intrinsics[..]),
+            "omni" => {
+                ensure!(!camera.intrinsics.is_empty(), "Missing xi for omni model");
+                (
+                    ProjectionModel::Unified {
+                        xi: camera.intrinsics[0],
+                    },
+                    &camera.intrinsics[1..],
+                )
+            }
+            "ds" => {
+                ensure!(
+                    camera.intrinsics.len() > 2,
+                    "Missing xi, alpha for ds model"
+                );
+                (
+                    ProjectionModel::DoubleSphere {
+                        xi: camera.intrinsics[0],
+                        alpha: camera.intrinsics[1],
+                    },
+                    &camera.intrinsics[2..],
+                )
+            }
+            camera_model => bail!("Unsupported Kalibr camera model {}", camera_model),
+        };
+        ensure!(
+            focal_params.len() == 4,
+            "Expected fu, fv, pu, pv intrinsics, got {:?}",
+            camera.intrinsics
+        );
+
+        let (model, distortion) = match (model, camera.distortion_model.as_deref()) {
+            (model, None | Some("none")) => (model, None),
+            (model, Some("radtan")) => (model, radtan(&camera.distortion_coeffs)?),
+            (ProjectionModel::Pinhole, Some("equidistant")) => {
+                (kannala_brandt(&camera.distortion_coeffs)?, None)
+            }
+            (_, Some(distortion_model)) => bail!(
+                "Unsupported Kalibr distortion model {} for camera model {}",
+                distortion_model,
+                camera.camera_model
+            ),
+        };
+
+        Ok(Self {
+            model,
+            distortion,
+            focal_length_x_px: focal_params[0],
+            focal_length_y_px: focal_params[1],
+            optical_center_x_px: focal_params[2] + PIXEL_CENTER_OFFSET_PX,
+            optical_center_y_px: focal_params[3] + PIXEL_CENTER_OFFSET_PX,
+            image_width_px: camera.resolution[0],
+            image_height_px: camera.resolution[1],
+        })
+    }
+
+    /// Load the camera with the given id from a COLMAP cameras.txt
+    ///
+    /// Supports the (simple) pinhole, (simple) radial, OpenCV and fisheye models. COLMAP uses the
+    /// same pixel center convention as this crate, so no offset is applied.
+    pub fn load_colmap<P: AsRef<Path>>(path: P, camera_id: u32) -> Result<Self> {
+        Self::from_colmap(&std::fs::read_to_string(path)?, camera_id)
+    }
+
+    fn from_colmap(content: &str, camera_id: u32) -> Result<Self> {
+        let line = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .find(|line| line.split_whitespace().next() == Some(&camera_id.to_string()))
+            .with_context(|| format!("Camera {} not found in cameras.txt", camera_id))?;
+
+        let mut fields = line.split_whitespace().skip(1);
+        let model_name = fields.next().context("Missing camera model")?;
+        let image_width_px: u32 = fields.next().context("Missing width")?.parse()?;
+        let image_height_px: u32 = fields.next().context("Missing height")?.parse()?;
+        let params = fields
+            .map(str::parse::<f32>)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let expected_params = match model_name {
+            "SIMPLE_PINHOLE" => 3,
+            "PINHOLE" | "SIMPLE_RADIAL" | "SIMPLE_RADIAL_FISHEYE" => 4,
+            "RADIAL" | "RADIAL_FISHEYE" => 5,
+            "OPENCV" | "OPENCV_FISHEYE" => 8,
+            "FULL_OPENCV" => 12,
+            model_name => bail!("Unsupported COLMAP camera model {}", model_name),
+        };
+        ensure!(
+            params.len() == expected_params,
+            "COLMAP model {} takes {} parameters, got {}",
+            model_name,
+            expected_params,
+            params.len()
+        );
+
+        // Models with a single focal length list f, cx, cy followed by distortion parameters
+        let (focal_x, focal_y, center_x, center_y, coefficients) = match model_name {
+            "SIMPLE_PINHOLE"
+            | "SIMPLE_RADIAL"
+            | "RADIAL"
+            | "SIMPLE_RADIAL_FISHEYE"
+            | "RADIAL_FISHEYE" => (params[0], params[0], params[1], params[2], &params[3..]),
+            _ => (params[0], params[1], params[2], params[3], &params[4..]),
+        };
+        let (model, distortion) = match model_name {
+            "SIMPLE_PINHOLE" | "PINHOLE" => (ProjectionModel::Pinhole, None),
+            "SIMPLE_RADIAL" | "RADIAL" | "OPENCV" => {
+                (ProjectionModel::Pinhole, radtan(coefficients)?)
+            }
+            "FULL_OPENCV" => {
+                ensure!(
+                    coefficients[5..].iter().all(|c| *c == 0.0),
+                    "Rational distortion coefficients k4, k5, k6 are not supported"
+                );
+                (ProjectionModel::Pinhole, radtan(&coefficients[..5])?)
+            }
+            _ => (kannala_brandt(coefficients)?, None),
+        };
+
+        Ok(Self {
+            model,
+            distortion,
+            focal_length_x_px: focal_x,
+            focal_length_y_px: focal_y,
+            optical_center_x_px: center_x,
+            optical_center_y_px: center_y,
+            image_width_px,
+            image_height_px,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENCV_YAML: &str = "%YAML:1.0
+---
+image_width: 640
+image_height: 480
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 500., 0., 319.5, 0., 510., 239.5, 0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 1
+   cols: 5
+   dt: d
+   data: [ -0.25, 0.125, 0.001, -0.002, 0. ]
+";
+
+    const OPENCV_OMNIDIR_YAML: &str = "%YAML:1.0
+---
+image_width: 1280
+image_height: 960
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 700., 0., 639.5, 0., 700., 479.5, 0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 1
+   cols: 4
+   dt: d
+   data: [ 0., 0., 0., 0. ]
+xi: !!opencv-matrix
+   rows: 1
+   cols: 1
+   dt: d
+   data: [ 1.5 ]
+";
+
+    const ROS_FISHEYE_YAML: &str = "image_width: 1280
+image_height: 1024
+camera_name: fisheye
+camera_matrix:
+  rows: 3
+  cols: 3
+  data: [400, 0, 639.5, 0, 400, 511.5, 0, 0, 1]
+distortion_model: equidistant
+distortion_coefficients:
+  rows: 1
+  cols: 4
+  data: [0.5, -0.25, 0.125, 0]
+";
+
+    const KALIBR_CAMCHAIN: &str = "cam0:
+  camera_model: ds
+  intrinsics: [-0.25, 0.5, 350.0, 351.0, 319.5, 239.5]
+  distortion_model: none
+  distortion_coeffs: []
+  resolution: [640, 480]
+  rostopic: /cam0/image_raw
+cam1:
+  camera_model: pinhole
+  intrinsics: [460.0, 459.0, 367.5, 248.5]
+  distortion_model: equidistant
+  distortion_coeffs: [0.5, 0.25, 0.0, 0.0]
+  resolution: [752, 480]
+  rostopic: /cam1/image_raw
+";
+
+    const COLMAP_CAMERAS: &str = "# Camera list with one line of data per camera:
+#   CAMERA_ID, MODEL, WIDTH, HEIGHT, PARAMS[]
+# Number of cameras: 2
+1 SIMPLE_RADIAL 640 480 500 320 240 -0.25
+2 OPENCV_FISHEYE 1024 768 300 301 512 384 0.5 0.25 0 0
+";
+
+    #[test]
+    fn opencv_pinhole_with_radtan() {
+        let intrinsics = Intrinsics::from_opencv_yaml(OPENCV_YAML).unwrap();
+        assert_eq!(intrinsics.model, ProjectionModel::Pinhole);
+        assert_eq!(
+            intrinsics.distortion,
+            Some(Distortion {
+                k1: -0.25,
+                k2: 0.125,
+                p1: 0.001,
+                p2: -0.002,
+                k3: 0.0,
+            })
+        );
+        assert_eq!(intrinsics.focal_length_x_px, 500.0);
+        assert_eq!(intrinsics.focal_length_y_px, 510.0);
+        assert_eq!(intrinsics.optical_center_x_px, 320.0);
+        assert_eq!(intrinsics.optical_center_y_px, 240.0);
+        assert_eq!(
+            (intrinsics.image_width_px, intrinsics.image_height_px),
+            (640, 480)
+        );
+    }
+
+    #[test]
+    fn opencv_omnidir_is_unified() {
+        let intrinsics = Intrinsics::from_opencv_yaml(OPENCV_OMNIDIR_YAML).unwrap();
+        assert_eq!(intrinsics.model, ProjectionModel::Unified { xi: 1.5 });
+        assert_eq!(intrinsics.distortion, None);
+        assert_eq!(intrinsics.optical_center_x_px, 640.0);
+        assert_eq!(intrinsics.optical_center_y_px, 480.0);
+    }
+
+    #[test]
+    fn ros_equidistant_is_kannala_brandt() {
+        let intrinsics = Intrinsics::from_opencv_yaml(ROS_FISHEYE_YAML).unwrap();
+        assert_eq!(
+            intrinsics.model,
+            ProjectionModel::KannalaBrandt {
+                k1: 0.5,
+                k2: -0.25,
+                k3: 0.125,
+                k4: 0.0,
+            }
+        );
+        assert_eq!(intrinsics.distortion, None);
+        assert_eq!(intrinsics.optical_center_x_px, 640.0);
+        assert_eq!(intrinsics.optical_center_y_px, 512.0);
+    }
+
+    #[test]
+    fn kalibr_double_sphere() {
+        let intrinsics = Intrinsics::from_kalibr(KALIBR_CAMCHAIN, "cam0").unwrap();
+        assert_eq!(
+            intrinsics.model,
+            ProjectionModel::DoubleSphere {
+                xi: -0.25,
+                alpha: 0.5,
+            }
+        );
+        assert_eq!(intrinsics.distortion, None);
+        assert_eq!(intrinsics.focal_length_x_px, 350.0);
+        assert_eq!(intrinsics.focal_length_y_px, 351.0);
+        assert_eq!(intrinsics.optical_center_x_px, 320.0);
+        assert_eq!(intrinsics.optical_center_y_px, 240.0);
+    }
+
+    #[test]
+    fn kalibr_pinhole_equidistant_is_kannala_brandt() {
+        let intrinsics = Intrinsics::from_kalibr(KALIBR_CAMCHAIN, "cam1").unwrap();
+        assert_eq!(
+            intrinsics.model,
+            ProjectionModel::KannalaBrandt {
+                k1: 0.5,
+                k2: 0.25,
+                k3: 0.0,
+                k4: 0.0,
+            }
+        );
+        assert_eq!(intrinsics.optical_center_x_px, 368.0);
+        assert_eq!(intrinsics.optical_center_y_px, 249.0);
+        assert_eq!(
+            (intrinsics.image_width_px, intrinsics.image_height_px),
+            (752, 480)
+        );
+        assert!(Intrinsics::from_kalibr(KALIBR_CAMCHAIN, "cam2").is_err());
+    }
+
+    #[test]
+    fn colmap_keeps_the_optical_center() {
+        let intrinsics = Intrinsics::from_colmap(COLMAP_CAMERAS, 1).unwrap();
+        assert_eq!(intrinsics.model, ProjectionModel::Pinhole);
+        assert_eq!(
+            intrinsics.distortion,
+            Some(Distortion {
+                k1: -0.25,
+                k2: 0.0,
+                p1: 0.0,
+                p2: 0.0,
+                k3: 0.0,
+            })
+        );
+        assert_eq!(intrinsics.focal_length_x_px, 500.0);
+        assert_eq!(intrinsics.focal_length_y_px, 500.0);
+        assert_eq!(intrinsics.optical_center_x_px, 320.0);
+        assert_eq!(intrinsics.optical_center_y_px, 240.0);
+    }
+
+    #[test]
+    fn colmap_fisheye_is_kannala_brandt() {
+        let intrinsics = Intrinsics::from_colmap(COLMAP_CAMERAS, 2).unwrap();
+        assert_eq!(
+            intrinsics.model,
+            ProjectionModel::KannalaBrandt {
+                k1: 0.5,
+                k2: 0.25,
+                k3: 0.0,
+                k4: 0.0,
+            }
+        );
+        assert_eq!(intrinsics.focal_length_x_px, 300.0);
+        assert_eq!(intrinsics.focal_length_y_px, 301.0);
+        assert_eq!(intrinsics.optical_center_x_px, 512.0);
+        assert_eq!(intrinsics.optical_center_y_px, 384.0);
+        assert!(Intrinsics::from_colmap(COLMAP_CAMERAS, 3).is_err());
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            CalibrationFormat::detect(Path::new("camera_params.toml")).unwrap(),
+            CalibrationFormat::Toml
+        );
+        assert_eq!(
+            CalibrationFormat::detect(Path::new("sparse/cameras.txt")).unwrap(),
+            CalibrationFormat::Colmap
+        );
+        assert!(CalibrationFormat::detect(Path::new("calibration.json")).is_err());
+    }
+}
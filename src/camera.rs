@@ -1,9 +1,14 @@
 use std::path::Path;
 
+use std::f32::consts::{FRAC_PI_2, PI};
+
 use anyhow::{bail, Result};
-use nalgebra::{Matrix4, Point2, Point3, Vector3};
+use image::{ImageBuffer, Luma};
+use nalgebra::{Matrix3, Matrix4, Point2, Point3, Vector3};
 use serde::{Deserialize, Serialize};
 
+use crate::depth::{DepthMapping, DEPTH_RANGE_M};
+use crate::geodesy::curvature_drop_m;
 use crate::Coords;
 
 #[repr(C)]
@@ -11,39 +16,88 @@ use crate::Coords;
 /// Byte representation of camera parameters for use in the shader
 pub struct CameraUniform {
     view: [[f32; 4]; 4],
-    xi: f32,
+    /// Model specific parameters, see ProjectionModel::shader_params
+    params: [f32; 4],
+    /// Distortion coefficients k1, k2, p1, p2
+    distortion: [f32; 4],
+    model: u32,
     fx: f32,
     fy: f32,
     cx: f32,
     cy: f32,
+    k3: f32,
+    /// Drop of the terrain per squared meter of horizontal distance from the camera
+    earth_curvature: f32,
+    /// Longitude added to the vertices on the far side of the equirectangular seam, see
+    /// `set_longitude_wrap`
+    longitude_wrap: f32,
+    /// Position of the camera relative to the origin of the vertices
+    position: [f32; 4],
+    /// Distance to depth buffer mapping, see DepthMapping
+    depth_mode: u32,
+    depth_near_m: f32,
+    depth_far_m: f32,
     /// 16 byte padding
-    dummy: [f32; 3],
+    dummy2: f32,
 }
 
 impl CameraUniform {
     pub fn new() -> Self {
         Self {
             view: Matrix4::identity().into(),
-            xi: 0.0,
+            params: [0.0; 4],
+            distortion: [0.0; 4],
+            model: 0,
             fx: 0.0,
             fy: 0.0,
             cx: 0.0,
             cy: 0.0,
-            dummy: [0.0, 0.0, 0.0],
+            k3: 0.0,
+            earth_curvature: 0.0,
+            longitude_wrap: 0.0,
+            position: [0.0, 0.0, 0.0, 1.0],
+            depth_mode: 0,
+            depth_near_m: 0.0,
+            depth_far_m: DEPTH_RANGE_M,
+            dummy2: 0.0,
         }
     }
 
-    /// Recalculate camera parameters from a given config
-    pub fn update(&mut self, camera: &Camera) {
-        self.view = (camera.calc_matrix()).into();
+    /// Recalculate camera parameters from a given config, for vertices relative to the origin
+    pub fn update(&mut self, camera: &Camera, origin: Coords) {
+        self.view = (camera.calc_matrix(origin)).into();
+        self.position = (camera.position - origin).cast::<f32>().push(1.0).into();
         let intrinsics = &camera.intrinsics;
-        self.xi = camera.intrinsics.xi;
+        self.params = intrinsics.model.shader_params();
+        self.model = intrinsics.model.shader_id();
+        let distortion = intrinsics.distortion.unwrap_or_default();
+        self.distortion = [distortion.k1, distortion.k2, distortion.p1, distortion.p2];
+        self.k3 = distortion.k3;
         // Change parameters from [0, w] x [0, h] to [-1, 1] x [-1, 1] camera coordinates
         self.fx = 2.0 * intrinsics.focal_length_x_px / intrinsics.image_width_px as f32;
         self.fy = 2.0 * intrinsics.focal_length_y_px / intrinsics.image_height_px as f32;
         self.cx = 2.0 * intrinsics.optical_center_x_px / intrinsics.image_width_px as f32 - 1.0;
         self.cy = 2.0 * intrinsics.optical_center_y_px / intrinsics.image_height_px as f32 - 1.0;
     }
+
+    pub fn set_depth_mapping(&mut self, depth_mapping: &DepthMapping) {
+        self.depth_mode = depth_mapping.mode.shader_id();
+        self.depth_near_m = depth_mapping.near_m;
+        self.depth_far_m = depth_mapping.far_m;
+    }
+
+    /// Bend the terrain down with the horizontal distance from the camera, see `curvature_drop_m`
+    pub fn set_earth_curvature(&mut self, refraction_coefficient: Option<f32>) {
+        self.earth_curvature = refraction_coefficient
+            .map(|refraction_coefficient| curvature_drop_m(1.0, refraction_coefficient))
+            .unwrap_or(0.0);
+    }
+
+    /// Move the negative longitudes of an equirectangular camera by +2 pi, or the positive ones
+    /// by -2 pi, so the triangles behind the camera are drawn in one piece past the seam
+    pub fn set_longitude_wrap(&mut self, longitude_wrap: f32) {
+        self.longitude_wrap = longitude_wrap;
+    }
 }
 
 impl Default for CameraUniform {
@@ -52,10 +106,248 @@ impl Default for CameraUniform {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Projection from camera frame rays to normalized image coordinates
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum ProjectionModel {
+    /// Unified omnidirectional (fisheye) model
+    Unified {
+        /// Xi parameter for fisheye model
+        xi: f32,
+    },
+    /// Standard perspective projection
+    Pinhole,
+    /// Kannala-Brandt (OpenCV fisheye) model, all coefficients zero gives an equidistant fisheye
+    KannalaBrandt {
+        /// Coefficients of the odd polynomial theta_d = theta (1 + k1 theta^2 + ... + k4 theta^8)
+        #[serde(default)]
+        k1: f32,
+        #[serde(default)]
+        k2: f32,
+        #[serde(default)]
+        k3: f32,
+        #[serde(default)]
+        k4: f32,
+    },
+    /// Double sphere model (Usenko et al.), as calibrated by Basalt
+    DoubleSphere {
+        /// Offset between the two unit spheres
+        xi: f32,
+        /// Weight between the second sphere and the image plane
+        alpha: f32,
+    },
+    /// Equirectangular panorama, normalized image coordinates are longitude and latitude in radians
+    Equirectangular,
+    /// Parallel projection along the optical axis, normalized image coordinates are in meters
+    Orthographic,
+}
+
+/// Newton iterations to invert the Kannala-Brandt polynomial
+const KANNALA_BRANDT_MAX_ITERATIONS: usize = 20;
+
+/// Kannala-Brandt polynomial theta (1 + k1 theta^2 + ... + k4 theta^8) and its derivative
+fn kannala_brandt_polynomial(theta: f32, k: [f32; 4]) -> (f32, f32) {
+    let theta2 = theta * theta;
+    let value = theta * (1.0 + theta2 * (k[0] + theta2 * (k[1] + theta2 * (k[2] + theta2 * k[3]))));
+    let derivative = 1.0
+        + theta2
+            * (3.0 * k[0] + theta2 * (5.0 * k[1] + theta2 * (7.0 * k[2] + theta2 * 9.0 * k[3])));
+    (value, derivative)
+}
+
+impl ProjectionModel {
+    /// Model identifier used in the shader, has to match the constants in shader.wgsl
+    fn shader_id(&self) -> u32 {
+        match self {
+            ProjectionModel::Unified { .. } => 0,
+            ProjectionModel::Pinhole => 1,
+            ProjectionModel::KannalaBrandt { .. } => 2,
+            ProjectionModel::DoubleSphere { .. } => 3,
+            ProjectionModel::Equirectangular => 4,
+            ProjectionModel::Orthographic => 5,
+        }
+    }
+
+    /// Model parameters in the layout expected by the shader
+    fn shader_params(&self) -> [f32; 4] {
+        match *self {
+            ProjectionModel::Unified { xi } => [xi, 0.0, 0.0, 0.0],
+            ProjectionModel::Pinhole => [0.0; 4],
+            ProjectionModel::KannalaBrandt { k1, k2, k3, k4 } => [k1, k2, k3, k4],
+            ProjectionModel::DoubleSphere { xi, alpha } => [xi, alpha, 0.0, 0.0],
+            ProjectionModel::Equirectangular => [0.0; 4],
+            ProjectionModel::Orthographic => [0.0; 4],
+        }
+    }
+
+    /// Project a point in the camera frame to normalized image coordinates
+    pub fn project(&self, point_m: Point3<f32>) -> Point2<f32> {
+        match *self {
+            ProjectionModel::Unified { xi } => {
+                let norm = point_m.z + xi * point_m.coords.norm();
+                Point2::new(point_m.x / norm, point_m.y / norm)
+            }
+            ProjectionModel::Pinhole => Point2::new(point_m.x / point_m.z, point_m.y / point_m.z),
+            ProjectionModel::KannalaBrandt { k1, k2, k3, k4 } => {
+                let r = point_m.xy().coords.norm();
+                if r == 0.0 {
+                    return Point2::origin();
+                }
+                let theta = r.atan2(point_m.z);
+                let (theta_d, _) = kannala_brandt_polynomial(theta, [k1, k2, k3, k4]);
+                Point2::from(point_m.xy().coords * theta_d / r)
+            }
+            ProjectionModel::DoubleSphere { xi, alpha } => {
+                let d1 = point_m.coords.norm();
+                let z = xi * d1 + point_m.z;
+                let d2 = (point_m.xy().coords.norm_squared() + z * z).sqrt();
+                let norm = alpha * d2 + (1.0 - alpha) * z;
+                Point2::new(point_m.x / norm, point_m.y / norm)
+            }
+            ProjectionModel::Equirectangular => Point2::new(
+                point_m.x.atan2(point_m.z),
+                (point_m.y / point_m.coords.norm()).asin(),
+            ),
+            ProjectionModel::Orthographic => point_m.xy(),
+        }
+    }
+
+    /// Unit ray through normalized image coordinates, may point behind the camera
+    pub fn bearing(&self, point: Point2<f32>) -> Result<Vector3<f32>> {
+        let ray = match *self {
+            ProjectionModel::Unified { xi } => {
+                let norm2 = point.coords.norm_squared();
+                let arg = 1.0 + (1.0 - xi * xi) * norm2;
+                if arg < 0.0 {
+                    bail!("Point not in FOV")
+                }
+                let factor = (xi + arg.sqrt()) / (1.0 + norm2);
+                Vector3::new(factor * point.x, factor * point.y, factor - xi)
+            }
+            ProjectionModel::Pinhole => Vector3::new(point.x, point.y, 1.0),
+            ProjectionModel::KannalaBrandt { k1, k2, k3, k4 } => {
+                let theta_d = point.coords.norm();
+                let mut theta = theta_d;
+                for _ in 0..KANNALA_BRANDT_MAX_ITERATIONS {
+                    let (value, derivative) = kannala_brandt_polynomial(theta, [k1, k2, k3, k4]);
+                    if (value - theta_d).abs() < 1e-7 {
+                        break;
+                    }
+                    if derivative <= 0.0 {
+                        bail!("Point not in FOV")
+                    }
+                    theta -= (value - theta_d) / derivative;
+                }
+                if !(0.0..PI).contains(&theta) {
+                    bail!("Point not in FOV")
+                }
+                if theta_d == 0.0 {
+                    Vector3::new(0.0, 0.0, 1.0)
+                } else {
+                    let s = theta.sin() / theta_d;
+                    Vector3::new(s * point.x, s * point.y, theta.cos())
+                }
+            }
+            ProjectionModel::DoubleSphere { xi, alpha } => {
+                let norm2 = point.coords.norm_squared();
+                let arg = 1.0 - (2.0 * alpha - 1.0) * norm2;
+                if arg < 0.0 {
+                    bail!("Point not in FOV")
+                }
+                let mz = (1.0 - alpha * alpha * norm2) / (alpha * arg.sqrt() + 1.0 - alpha);
+                let k = (mz * xi + (mz * mz + (1.0 - xi * xi) * norm2).sqrt()) / (mz * mz + norm2);
+                Vector3::new(k * point.x, k * point.y, k * mz - xi)
+            }
+            ProjectionModel::Equirectangular => {
+                let (longitude, latitude) = (point.x, point.y);
+                if longitude.abs() > PI || latitude.abs() > FRAC_PI_2 {
+                    bail!("Point not in FOV")
+                }
+                Vector3::new(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    latitude.cos() * longitude.cos(),
+                )
+            }
+            // All rays are parallel, the image coordinates only offset their origin
+            ProjectionModel::Orthographic => Vector3::new(0.0, 0.0, 1.0),
+        };
+        Ok(ray.normalize())
+    }
+
+    /// Ray through normalized image coordinates, scaled to unit z
+    pub fn unproject(&self, point: Point2<f32>) -> Result<Vector3<f32>> {
+        let ray = self.bearing(point)?;
+        if ray.z <= 0.0 {
+            bail!("Point not in FOV")
+        }
+        Ok(ray / ray.z)
+    }
+}
+
+/// Iterations of the fixed point undistortion
+const UNDISTORT_MAX_ITERATIONS: usize = 20;
+
+/// Brown-Conrady (radial-tangential) lens distortion in the OpenCV convention
+/// Applied to normalized image coordinates after the projection model, with y pointing down
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub struct Distortion {
+    /// Radial coefficients
+    pub k1: f32,
+    pub k2: f32,
+    /// Tangential coefficients
+    pub p1: f32,
+    pub p2: f32,
+    /// Sixth order radial coefficient
+    pub k3: f32,
+}
+
+impl Distortion {
+    /// Distort normalized image coordinates
+    pub fn distort(&self, point: Point2<f32>) -> Point2<f32> {
+        let r2 = point.coords.norm_squared();
+        let radial = 1.0 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+        let xy = point.x * point.y;
+        Point2::new(
+            point.x * radial + 2.0 * self.p1 * xy + self.p2 * (r2 + 2.0 * point.x * point.x),
+            point.y * radial + self.p1 * (r2 + 2.0 * point.y * point.y) + 2.0 * self.p2 * xy,
+        )
+    }
+
+    /// Iteratively invert the distortion of normalized image coordinates
+    pub fn undistort(&self, distorted: Point2<f32>) -> Result<Point2<f32>> {
+        let mut point = distorted;
+        for _ in 0..UNDISTORT_MAX_ITERATIONS {
+            let error = self.distort(point) - distorted;
+            if error.norm() < 1e-7 {
+                return Ok(point);
+            }
+            let r2 = point.coords.norm_squared();
+            let radial = 1.0 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+            let xy = point.x * point.y;
+            let tangential_x = 2.0 * self.p1 * xy + self.p2 * (r2 + 2.0 * point.x * point.x);
+            let tangential_y = self.p1 * (r2 + 2.0 * point.y * point.y) + 2.0 * self.p2 * xy;
+            point = Point2::new(
+                (distorted.x - tangential_x) / radial,
+                (distorted.y - tangential_y) / radial,
+            );
+        }
+        if (self.distort(point) - distorted).norm() > 1e-4 {
+            bail!("Undistortion did not converge")
+        }
+        Ok(point)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Intrinsics {
-    /// Xi parameter for fisheye model
-    pub xi: f32,
+    /// Projection model and its parameters, selected by the `model` key
+    #[serde(flatten)]
+    pub model: ProjectionModel,
+    /// Optional lens distortion applied on top of the projection model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distortion: Option<Distortion>,
     /// Focal length for x and y axis in pixels
     pub focal_length_x_px: f32,
     pub focal_length_y_px: f32,
@@ -68,8 +360,61 @@ pub struct Intrinsics {
 }
 
 impl Intrinsics {
+    /// Full 360 x 180 degree equirectangular panorama with square pixels
+    pub fn equirectangular(image_width_px: u32) -> Self {
+        let image_height_px = image_width_px / 2;
+        Self {
+            model: ProjectionModel::Equirectangular,
+            distortion: None,
+            focal_length_x_px: image_width_px as f32 / (2.0 * PI),
+            focal_length_y_px: image_height_px as f32 / PI,
+            optical_center_x_px: image_width_px as f32 / 2.0,
+            optical_center_y_px: image_height_px as f32 / 2.0,
+            image_width_px,
+            image_height_px,
+        }
+    }
+
+    /// Orthographic image with square pixels of the given ground sampling distance
+    pub fn orthographic(gsd_m: f32, image_width_px: u32, image_height_px: u32) -> Self {
+        Self {
+            model: ProjectionModel::Orthographic,
+            distortion: None,
+            focal_length_x_px: 1.0 / gsd_m,
+            focal_length_y_px: 1.0 / gsd_m,
+            optical_center_x_px: image_width_px as f32 / 2.0,
+            optical_center_y_px: image_height_px as f32 / 2.0,
+            image_width_px,
+            image_height_px,
+        }
+    }
+
+    /// Intrinsics for rendering at a different resolution than the one calibrated
+    ///
+    /// Focal lengths and optical center scale with the image, distortion is resolution independent.
+    pub fn scaled(&self, image_width_px: u32, image_height_px: u32) -> Self {
+        let scale_x = image_width_px as f32 / self.image_width_px as f32;
+        let scale_y = image_height_px as f32 / self.image_height_px as f32;
+        Self {
+            focal_length_x_px: self.focal_length_x_px * scale_x,
+            focal_length_y_px: self.focal_length_y_px * scale_y,
+            optical_center_x_px: self.optical_center_x_px * scale_x,
+            optical_center_y_px: self.optical_center_y_px * scale_y,
+            image_width_px,
+            image_height_px,
+            ..self.clone()
+        }
+    }
+
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+        let mut params: toml::Value = toml::from_str(&std::fs::read_to_string(path)?)?;
+        // Parameter files without a model key predate the other models and are unified
+        if let Some(table) = params.as_table_mut() {
+            table
+                .entry("model")
+                .or_insert_with(|| toml::Value::from("unified"));
+        }
+        Ok(params.try_into()?)
     }
 }
 
@@ -91,38 +436,318 @@ impl Camera {
         }
     }
 
-    pub fn calc_matrix(&self) -> Matrix4<f32> {
-        Matrix4::look_at_rh(&self.position, &(self.position + self.forward), &self.up)
+    /// View matrix for world coordinates relative to the given origin
+    ///
+    /// Large LV95 coordinates lose their precision in f32, so the origin should be close to the
+    /// rendered geometry.
+    pub fn calc_matrix(&self, origin: Coords) -> Matrix4<f32> {
+        let eye = Point3::from((self.position - origin).cast::<f32>());
+        Matrix4::look_at_rh(&eye, &(eye + self.forward), &self.up)
+    }
+
+    /// Rotation from the camera frame (x right, y down, z forward) to world coordinates
+    pub fn rotation(&self) -> Matrix3<f32> {
+        let forward = self.forward.normalize();
+        let right = forward.cross(&self.up).normalize();
+        let down = forward.cross(&right);
+        Matrix3::from_columns(&[right, down, forward])
     }
 
     /// Project world (camera frame, positive z) into pixel (screen) coordinates
-    pub fn project(&self, point_m: Coords) -> Point2<f32> {
-        let norm: f32 = point_m.z + self.intrinsics.xi * point_m.coords.norm();
+    pub fn project(&self, point_m: Point3<f32>) -> Point2<f32> {
+        let mut point = self.intrinsics.model.project(point_m);
+        if let Some(distortion) = &self.intrinsics.distortion {
+            point = distortion.distort(point);
+        }
         Point2::new(
-            self.intrinsics.focal_length_x_px * point_m.x / norm
-                + self.intrinsics.optical_center_x_px,
-            self.intrinsics.focal_length_y_px * point_m.y / norm
-                + self.intrinsics.optical_center_y_px,
+            self.intrinsics.focal_length_x_px * point.x + self.intrinsics.optical_center_x_px,
+            self.intrinsics.focal_length_y_px * point.y + self.intrinsics.optical_center_y_px,
         )
     }
 
     /// Project  pixel (screen) into world (camera frame, positive z) coordinates
-    pub fn unproject(&self, mut point_px: Point2<f32>, depth_m: f32) -> Result<Coords> {
+    pub fn unproject(&self, point_px: Point2<f32>, depth_m: f32) -> Result<Point3<f32>> {
+        let point = self.undistort(point_px)?;
+        if self.intrinsics.model == ProjectionModel::Orthographic {
+            return Ok(Point3::new(point.x, point.y, depth_m));
+        }
+        let ray = self.intrinsics.model.unproject(point)?;
+        Ok(Point3::from(depth_m * ray))
+    }
+
+    /// Unit ray (camera frame) through a pixel, also covering fields of view beyond 180 degrees
+    pub fn unproject_bearing(&self, point_px: Point2<f32>) -> Result<Vector3<f32>> {
+        self.intrinsics.model.bearing(self.undistort(point_px)?)
+    }
+
+    /// Horizontal field of view through the optical center in radians
+    pub fn horizontal_fov_rad(&self) -> f32 {
+        let center_y_px = self.intrinsics.optical_center_y_px;
+        self.axis_angle_rad(Point2::new(0.0, center_y_px))
+            + self.axis_angle_rad(Point2::new(
+                self.intrinsics.image_width_px as f32,
+                center_y_px,
+            ))
+    }
+
+    /// Vertical field of view through the optical center in radians
+    pub fn vertical_fov_rad(&self) -> f32 {
+        let center_x_px = self.intrinsics.optical_center_x_px;
+        self.axis_angle_rad(Point2::new(center_x_px, 0.0))
+            + self.axis_angle_rad(Point2::new(
+                center_x_px,
+                self.intrinsics.image_height_px as f32,
+            ))
+    }
+
+    /// Field of view between the top left and bottom right image corners in radians
+    pub fn diagonal_fov_rad(&self) -> f32 {
+        self.axis_angle_rad(Point2::new(0.0, 0.0))
+            + self.axis_angle_rad(Point2::new(
+                self.intrinsics.image_width_px as f32,
+                self.intrinsics.image_height_px as f32,
+            ))
+    }
+
+    /// Angle between the optical axis and the outermost valid ray on the line from the optical
+    /// center to the given pixel
+    fn axis_angle_rad(&self, edge_px: Point2<f32>) -> f32 {
+        let center_px = Point2::new(
+            self.intrinsics.optical_center_x_px,
+            self.intrinsics.optical_center_y_px,
+        );
+        let steps = (edge_px - center_px).norm().ceil() as usize;
+        for step in 0..steps {
+            let point_px = edge_px + (center_px - edge_px) * (step as f32 / steps as f32);
+            if let Ok(bearing) = self.unproject_bearing(point_px) {
+                return bearing.z.clamp(-1.0, 1.0).acos();
+            }
+        }
+        0.0
+    }
+
+    /// Mask of the pixels (at their centers) that `unproject` accepts, 255 if valid
+    ///
+    /// Rays more than 90 degrees off the optical axis are invalid, although `unproject_bearing`
+    /// and the bearing table cover them.
+    pub fn valid_mask(&self) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(
+            self.intrinsics.image_width_px,
+            self.intrinsics.image_height_px,
+            |x, y| {
+                let valid = self
+                    .unproject(Point2::new(x as f32 + 0.5, y as f32 + 0.5), 1.0)
+                    .is_ok();
+                Luma([if valid { 255 } else { 0 }])
+            },
+        )
+    }
+
+    /// Unit ray through each pixel center in the camera frame (x right, y down, z forward)
+    ///
+    /// The table has shape (height, width, 3), pixels without a valid ray are NaN.
+    pub fn bearing_table(&self) -> ndarray::Array3<f32> {
+        let width = self.intrinsics.image_width_px as usize;
+        let height = self.intrinsics.image_height_px as usize;
+        let mut table = ndarray::Array3::from_elem((height, width, 3), f32::NAN);
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = Point2::new(x as f32 + 0.5, y as f32 + 0.5);
+                if let Ok(ray) = self.unproject_bearing(pixel) {
+                    for (axis, value) in ray.iter().enumerate() {
+                        table[[y, x, axis]] = *value;
+                    }
+                }
+            }
+        }
+        table
+    }
+
+    /// Normalized and undistorted image coordinates of a pixel
+    fn undistort(&self, mut point_px: Point2<f32>) -> Result<Point2<f32>> {
         point_px.x =
             (point_px.x - self.intrinsics.optical_center_x_px) / self.intrinsics.focal_length_x_px;
         point_px.y =
             (point_px.y - self.intrinsics.optical_center_y_px) / self.intrinsics.focal_length_y_px;
 
-        let norm2 = point_px.coords.norm_squared();
-        let xi2 = self.intrinsics.xi * self.intrinsics.xi;
-        let normxi2 = norm2 * xi2;
+        if let Some(distortion) = &self.intrinsics.distortion {
+            point_px = distortion.undistort(point_px)?;
+        }
+        Ok(point_px)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_4;
 
-        let arg = 1.0 + norm2 - normxi2;
-        if arg <= 0.0 {
-            bail!("Point not in FOV")
+    use super::*;
+
+    /// Unit directions at even steps up to the given angle from the optical axis, at azimuths off
+    /// the image axes
+    fn directions(max_angle_rad: f32) -> Vec<Vector3<f32>> {
+        let mut directions = Vec::new();
+        for i in 0..=8 {
+            let angle = max_angle_rad * i as f32 / 8.0;
+            for j in 0..12 {
+                let azimuth = 2.0 * PI * (j as f32 + 0.5) / 12.0;
+                directions.push(Vector3::new(
+                    angle.sin() * azimuth.cos(),
+                    angle.sin() * azimuth.sin(),
+                    angle.cos(),
+                ));
+            }
+        }
+        directions
+    }
+
+    /// Projecting points in the given directions and taking the bearing of their image
+    /// coordinates gives the directions back
+    fn assert_round_trip(model: ProjectionModel, max_angle_rad: f32) {
+        for direction in directions(max_angle_rad) {
+            let point = model.project(Point3::from(5.0 * direction));
+            let bearing = model.bearing(point).unwrap();
+            assert!(
+                (bearing - direction).norm() < 1e-4,
+                "{:?}: {} projects to {} with bearing {}",
+                model,
+                direction,
+                point,
+                bearing
+            );
+        }
+    }
+
+    #[test]
+    fn unified_round_trip() {
+        assert_round_trip(ProjectionModel::Unified { xi: 0.0 }, 60f32.to_radians());
+        assert_round_trip(ProjectionModel::Unified { xi: 1.5 }, 100f32.to_radians());
+    }
+
+    #[test]
+    fn pinhole_round_trip() {
+        let model = ProjectionModel::Pinhole;
+        assert_round_trip(model, 60f32.to_radians());
+
+        let point_m = Point3::new(1.0, -2.0, 4.0);
+        let ray = model.unproject(model.project(point_m)).unwrap();
+        assert!((ray - point_m.coords / 4.0).norm() < 1e-6);
+    }
+
+    #[test]
+    fn kannala_brandt_round_trip() {
+        let equidistant = ProjectionModel::KannalaBrandt {
+            k1: 0.0,
+            k2: 0.0,
+            k3: 0.0,
+            k4: 0.0,
+        };
+        assert_round_trip(equidistant, 100f32.to_radians());
+        // Image radius of the equidistant fisheye is the angle from the optical axis
+        let point = equidistant.project(Point3::new(1.0, 0.0, 1.0));
+        assert!((point - Point2::new(FRAC_PI_4, 0.0)).norm() < 1e-6);
+
+        let model = ProjectionModel::KannalaBrandt {
+            k1: -0.01,
+            k2: 0.005,
+            k3: -0.001,
+            k4: 0.0002,
+        };
+        assert_round_trip(model, 100f32.to_radians());
+    }
+
+    #[test]
+    fn double_sphere_round_trip() {
+        // Without the sphere offset this is the unified model with xi = alpha / (1 - alpha) and a
+        // focal length divided by 1 - alpha
+        let model = ProjectionModel::DoubleSphere {
+            xi: 0.0,
+            alpha: 0.6,
+        };
+        let unified = ProjectionModel::Unified { xi: 1.5 };
+        let point_m = Point3::new(2.0, -1.0, 3.0);
+        let unified_point = unified.project(point_m).coords / 0.4;
+        assert!((model.project(point_m).coords - unified_point).norm() < 1e-6);
+
+        assert_round_trip(model, 100f32.to_radians());
+        assert_round_trip(
+            ProjectionModel::DoubleSphere {
+                xi: -0.2,
+                alpha: 0.6,
+            },
+            100f32.to_radians(),
+        );
+    }
+
+    #[test]
+    fn undistort_inverts_distort() {
+        let distortion = Distortion {
+            k1: -0.28,
+            k2: 0.07,
+            p1: 2e-4,
+            p2: -1e-4,
+            k3: 0.01,
+        };
+        for i in -4..=4 {
+            for j in -3..=3 {
+                let point = Point2::new(0.15 * i as f32, 0.15 * j as f32);
+                let undistorted = distortion.undistort(distortion.distort(point)).unwrap();
+                assert!(
+                    (undistorted - point).norm() < 1e-5,
+                    "{} undistorts to {}",
+                    point,
+                    undistorted
+                );
+            }
+        }
+
+        // Pixels of a distorted camera unproject to the points projecting to them
+        let camera = Camera::new(
+            Coords::new(0.0, 0.0, 0.0),
+            Intrinsics {
+                model: ProjectionModel::Pinhole,
+                distortion: Some(distortion),
+                focal_length_x_px: 500.0,
+                focal_length_y_px: 510.0,
+                optical_center_x_px: 320.0,
+                optical_center_y_px: 240.0,
+                image_width_px: 640,
+                image_height_px: 480,
+            },
+        );
+        let point_m = Point3::new(-1.5, 1.0, 4.0);
+        let unprojected = camera.unproject(camera.project(point_m), 4.0).unwrap();
+        assert!((unprojected - point_m).norm() < 1e-4);
+    }
+
+    #[test]
+    fn equirectangular_round_trip() {
+        // Covers the rays behind the camera as well
+        assert_round_trip(ProjectionModel::Equirectangular, PI);
+
+        let camera = Camera::new(Coords::new(0.0, 0.0, 0.0), Intrinsics::equirectangular(512));
+        let left = camera.project(Point3::new(-1.0, 0.0, 0.0));
+        assert!((left - Point2::new(128.0, 128.0)).norm() < 1e-3);
+        let up = camera.project(Point3::new(0.0, -1.0, 1e-3));
+        assert!(up.y.abs() < 0.1);
+    }
+
+    #[test]
+    fn orthographic_round_trip() {
+        let camera = Camera::new(
+            Coords::new(0.0, 0.0, 0.0),
+            Intrinsics::orthographic(0.5, 64, 48),
+        );
+        let center = camera.project(Point3::new(0.0, 0.0, 100.0));
+        assert!((center - Point2::new(32.0, 24.0)).norm() < 1e-6);
+
+        // Pixels are gsd apart independent of the depth
+        for depth_m in [1.0, 100.0, 1000.0] {
+            let point_m = Point3::new(3.0, -2.0, depth_m);
+            let point_px = camera.project(point_m);
+            assert!((point_px - Point2::new(38.0, 20.0)).norm() < 1e-4);
+            let unprojected = camera.unproject(point_px, depth_m).unwrap();
+            assert!((unprojected - point_m).norm() < 1e-4);
         }
-        let a = self.intrinsics.xi + arg.sqrt();
-        let s = a / (a - self.intrinsics.xi * (norm2 + 1.0));
-        Ok(depth_m * Point3::new(s * point_px.x, s * point_px.y, 1.0))
     }
 }
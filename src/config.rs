@@ -2,6 +2,20 @@ use std::path::PathBuf;
 
 use anyhow::{ensure, Result};
 use clap::Parser;
+use serde::Serialize;
+
+/// Elevation model the terrain tiles are loaded from
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ElevationSource {
+    /// swisssurface3d with buildings and vegetation, tiles without it are skipped
+    Surface,
+    /// Bare earth swissalti3d without buildings and vegetation
+    Terrain,
+    /// swisssurface3d, falling back to swissalti3d for tiles without it
+    #[default]
+    SurfaceWithFallback,
+}
 
 #[derive(Clone, Debug, Parser)]
 pub struct StorageConfig {
@@ -17,22 +31,49 @@ pub struct StorageConfig {
     /// Maximum allowed image LOD to load, 0 means allowing the full resolution
     #[clap(long, default_value = "0")]
     pub image_max_lod: usize,
+    /// Elevation model to build the terrain from
+    #[clap(long, value_enum, default_value_t)]
+    pub elevation_source: ElevationSource,
+    /// Path to a geoid undulation GeoTIFF to convert WGS84 ellipsoidal heights to the
+    /// orthometric heights of the elevation models, e.g. CHGeo2004
+    #[clap(long)]
+    pub geoid_path: Option<PathBuf>,
+    /// Path to a directory containing swisstlm3d land cover GeoJSON exports in LV95 per tile,
+    /// needed to render labels. Shapefiles are not read, convert them with ogr2ogr -f GeoJSON
+    #[clap(long)]
+    pub landcover_dir: Option<PathBuf>,
 }
 
 impl StorageConfig {
     pub fn validate(&self) -> Result<()> {
         ensure!(
-            self.surface_dir.exists(),
+            self.elevation_source == ElevationSource::Terrain || self.surface_dir.exists(),
             "Unable to access swisstopo surface model dir"
         );
         ensure!(
-            self.alti_dir.exists(),
+            self.elevation_source == ElevationSource::Surface || self.alti_dir.exists(),
             "Unable to access swisstopo altitude model dir"
         );
         ensure!(
             self.image_dir.exists(),
             "Unable to access swisstopo ortho image dir"
         );
+        if let Some(geoid_path) = &self.geoid_path {
+            ensure!(geoid_path.exists(), "Unable to access geoid grid");
+        }
+        if let Some(landcover_dir) = &self.landcover_dir {
+            ensure!(landcover_dir.exists(), "Unable to access land cover dir");
+        }
+        Ok(())
+    }
+
+    /// Ensure the bare terrain the object height is measured from is accessible, also for the
+    /// surface elevation source
+    pub fn validate_object_height(&self) -> Result<()> {
+        ensure!(
+            self.alti_dir.exists(),
+            "Unable to access swisstopo altitude model dir for the object height"
+        );
         Ok(())
     }
 }
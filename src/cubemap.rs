@@ -0,0 +1,163 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use image::{ImageBuffer, Rgba};
+use nalgebra::{Point2, Vector3};
+use serde::Serialize;
+
+use crate::camera::{Camera, Intrinsics, ProjectionModel};
+use crate::Coords;
+
+/// Viewing directions (forward, up) of the faces in LV95, ordered +E, -E, +N, -N, +U, -U
+pub const FACE_DIRECTIONS: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+];
+
+/// File name suffixes of the faces, in the order of FACE_DIRECTIONS
+pub const FACE_NAMES: [&str; 6] = ["east", "west", "north", "south", "up", "down"];
+
+/// Six 90 degree pinhole views from one position, aligned with the LV95 axes
+#[derive(Debug, Default)]
+pub struct Cubemap {
+    pub position: Coords,
+    pub face_size_px: u32,
+    /// Color and depth of each face in the order of FACE_DIRECTIONS
+    pub faces_rgba: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    pub faces_depth: Vec<Vec<f32>>,
+}
+
+/// File names of the faces of a saved Cubemap, in the order of FACE_DIRECTIONS
+#[derive(Debug, Clone, Serialize)]
+pub struct CubemapPaths {
+    pub rgb_image_paths: Vec<PathBuf>,
+    /// Float32 range of shape (face_size_px, face_size_px), NaN for sky
+    pub depth_image_paths: Vec<PathBuf>,
+}
+
+impl Cubemap {
+    /// Pinhole intrinsics of a single face
+    pub fn face_intrinsics(face_size_px: u32) -> Intrinsics {
+        let center_px = face_size_px as f32 / 2.0;
+        Intrinsics {
+            model: ProjectionModel::Pinhole,
+            distortion: None,
+            focal_length_x_px: center_px,
+            focal_length_y_px: center_px,
+            optical_center_x_px: center_px,
+            optical_center_y_px: center_px,
+            image_width_px: face_size_px,
+            image_height_px: face_size_px,
+        }
+    }
+
+    /// Camera at the given position looking at the face with the given index
+    pub fn face_camera(position: Coords, face: usize, face_size_px: u32) -> Camera {
+        let (forward, up) = FACE_DIRECTIONS[face];
+        let mut camera = Camera::new(position, Self::face_intrinsics(face_size_px));
+        camera.forward = forward.into();
+        camera.up = up.into();
+        camera
+    }
+
+    /// Resample the faces into the image of a camera with arbitrary intrinsics and orientation
+    ///
+    /// The position of the camera is ignored, the cubemap position is used instead.
+    /// Depth values are metric distances along the ray and carry over unchanged. Pixels outside
+    /// of the field of view are transparent with a NaN depth like the sky.
+    pub fn resample(&self, camera: &Camera) -> (ImageBuffer<Rgba<u8>, Vec<u8>>, Vec<f32>) {
+        let width = camera.intrinsics.image_width_px;
+        let height = camera.intrinsics.image_height_px;
+        let rotation = camera.rotation();
+        let faces: Vec<Camera> = (0..FACE_DIRECTIONS.len())
+            .map(|face| Self::face_camera(self.position, face, self.face_size_px))
+            .collect();
+        let face_rotations: Vec<_> = faces.iter().map(|face| face.rotation()).collect();
+
+        let mut image_rgba = ImageBuffer::new(width, height);
+        let mut image_depth = vec![f32::NAN; (width * height) as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = Point2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let direction = match camera.unproject_bearing(pixel) {
+                    Ok(bearing) => rotation * bearing,
+                    Err(_) => continue,
+                };
+                // The face whose optical axis is closest to the direction contains it
+                let face = face_rotations
+                    .iter()
+                    .map(|face_rotation| face_rotation.column(2).dot(&direction))
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .unwrap()
+                    .0;
+                let local: Vector3<f32> = face_rotations[face].transpose() * direction;
+                let face_px = faces[face].project(local.into());
+                image_rgba.put_pixel(x, y, self.sample_rgba(face, face_px));
+                image_depth[(y * width + x) as usize] = self.sample_depth(face, face_px);
+            }
+        }
+        (image_rgba, image_depth)
+    }
+
+    /// Write each face next to the given path, adding the name of the face to its file name
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<CubemapPaths> {
+        let path = path.as_ref();
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut paths = CubemapPaths {
+            rgb_image_paths: Vec::with_capacity(FACE_NAMES.len()),
+            depth_image_paths: Vec::with_capacity(FACE_NAMES.len()),
+        };
+        for ((name, rgba), depth) in FACE_NAMES
+            .iter()
+            .zip(&self.faces_rgba)
+            .zip(&self.faces_depth)
+        {
+            let rgb_file_name = PathBuf::from(format!("{}_cube_{}.png", stem, name));
+            rgba.save(path.with_file_name(&rgb_file_name))?;
+            let depth_file_name = PathBuf::from(format!("{}_cube_{}.bin", stem, name));
+            std::fs::write(
+                path.with_file_name(&depth_file_name),
+                bytemuck::cast_slice(depth),
+            )?;
+            paths.rgb_image_paths.push(rgb_file_name);
+            paths.depth_image_paths.push(depth_file_name);
+        }
+        Ok(paths)
+    }
+
+    /// Bilinearly interpolated color of a face at continuous pixel coordinates
+    fn sample_rgba(&self, face: usize, point_px: Point2<f32>) -> Rgba<u8> {
+        let max_px = self.face_size_px as f32 - 1.0;
+        let x = (point_px.x - 0.5).clamp(0.0, max_px);
+        let y = (point_px.y - 0.5).clamp(0.0, max_px);
+        let (left, top) = (x.floor() as u32, y.floor() as u32);
+        let (right, bottom) = (
+            (left + 1).min(self.face_size_px - 1),
+            (top + 1).min(self.face_size_px - 1),
+        );
+        let (right_fac, bottom_fac) = (x - left as f32, y - top as f32);
+        let image = &self.faces_rgba[face];
+        let mut color = [0u8; 4];
+        for (channel, value) in color.iter_mut().enumerate() {
+            let top_val = image.get_pixel(left, top).0[channel] as f32 * (1.0 - right_fac)
+                + image.get_pixel(right, top).0[channel] as f32 * right_fac;
+            let bottom_val = image.get_pixel(left, bottom).0[channel] as f32 * (1.0 - right_fac)
+                + image.get_pixel(right, bottom).0[channel] as f32 * right_fac;
+            *value = (top_val * (1.0 - bottom_fac) + bottom_val * bottom_fac).round() as u8;
+        }
+        Rgba(color)
+    }
+
+    /// Depth of the closest face pixel, depth is not interpolated across discontinuities
+    fn sample_depth(&self, face: usize, point_px: Point2<f32>) -> f32 {
+        let max_px = self.face_size_px - 1;
+        let x = (point_px.x.max(0.0) as u32).min(max_px);
+        let y = (point_px.y.max(0.0) as u32).min(max_px);
+        self.faces_depth[face][(y * self.face_size_px + x) as usize]
+    }
+}
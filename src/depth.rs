@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+
+/// Far plane of the default depth mapping, used where there is no view range such as for
+/// orthophotos
+pub const DEPTH_RANGE_M: f32 = 10_000.0;
+/// Distance beyond the view range still covered by the far plane, for the tiles loaded around
+/// the view range and the terrain relief
+const FAR_MARGIN_M: f32 = 5_000.0;
+
+/// How distances from the camera are stored in the depth buffer
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DepthMode {
+    /// Distance over the far plane, losing precision with the view range
+    #[default]
+    Linear,
+    /// Near plane over distance, 1 at the near and 0 at the far plane, precise at all distances
+    /// with a float depth buffer
+    ReverseZ,
+    /// Logarithm of the distance, 0 at the near and 1 at the far plane
+    Logarithmic,
+}
+
+impl DepthMode {
+    /// Identifier of the mode in the shader
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            DepthMode::Linear => 0,
+            DepthMode::ReverseZ => 1,
+            DepthMode::Logarithmic => 2,
+        }
+    }
+}
+
+/// What the metric depth of a pixel measures
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DepthSemantics {
+    /// Euclidean distance from the camera along the ray of the pixel
+    #[default]
+    Range,
+    /// Distance along the optical axis, negative behind the camera for wide fields of view
+    ZDepth,
+}
+
+/// Encoding of the depth images written next to the renders
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct DepthEncoding {
+    /// Raw little endian float32 in row major order
+    pub dtype: &'static str,
+    pub unit: &'static str,
+    pub semantics: DepthSemantics,
+    /// Value of pixels where no terrain was rendered
+    pub sky: &'static str,
+}
+
+impl DepthEncoding {
+    pub fn new(semantics: DepthSemantics) -> Self {
+        DepthEncoding {
+            dtype: "float32",
+            unit: "m",
+            semantics,
+            sky: "nan",
+        }
+    }
+}
+
+/// Depth mode together with the planes used for a render
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct DepthMapping {
+    pub mode: DepthMode,
+    pub near_m: f32,
+    pub far_m: f32,
+}
+
+impl Default for DepthMapping {
+    fn default() -> Self {
+        DepthMapping {
+            mode: DepthMode::Linear,
+            near_m: 0.0,
+            far_m: DEPTH_RANGE_M,
+        }
+    }
+}
+
+impl DepthMapping {
+    /// Planes for a camera at the given height above the terrain seeing up to the view range
+    pub fn new(mode: DepthMode, agl_m: f32, view_range_m: f32) -> Self {
+        let far_m = view_range_m + agl_m.max(0.0) + FAR_MARGIN_M;
+        match mode {
+            DepthMode::Linear => DepthMapping {
+                mode,
+                near_m: 0.0,
+                far_m,
+            },
+            DepthMode::ReverseZ | DepthMode::Logarithmic => DepthMapping {
+                mode,
+                near_m: (0.01 * agl_m).clamp(0.1, 10.0),
+                far_m,
+            },
+        }
+    }
+
+    /// Whether closer surfaces have larger depth values
+    pub fn is_reversed(&self) -> bool {
+        self.mode == DepthMode::ReverseZ
+    }
+
+    /// Depth value of pixels where no terrain was rendered
+    pub fn sky_depth(&self) -> f32 {
+        if self.is_reversed() {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    /// Depth value of a distance from the camera, has to match shader.wgsl
+    pub fn depth(&self, distance_m: f32) -> f32 {
+        match self.mode {
+            DepthMode::Linear => distance_m / self.far_m,
+            DepthMode::ReverseZ => {
+                self.near_m * (self.far_m - distance_m) / (distance_m * (self.far_m - self.near_m))
+            }
+            DepthMode::Logarithmic => {
+                (distance_m / self.near_m).ln() / (self.far_m / self.near_m).ln()
+            }
+        }
+    }
+
+    /// Distance from the camera of a depth value, None for the sky
+    pub fn distance_m(&self, depth: f32) -> Option<f32> {
+        if depth == self.sky_depth() {
+            return None;
+        }
+        Some(match self.mode {
+            DepthMode::Linear => depth * self.far_m,
+            DepthMode::ReverseZ => {
+                self.near_m * self.far_m / (self.near_m + depth * (self.far_m - self.near_m))
+            }
+            DepthMode::Logarithmic => self.near_m * (self.far_m / self.near_m).powf(depth),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_inverts_depth() {
+        for mode in [
+            DepthMode::Linear,
+            DepthMode::ReverseZ,
+            DepthMode::Logarithmic,
+        ] {
+            let depth_mapping = DepthMapping::new(mode, 500.0, 20_000.0);
+            let mut previous_depth = None;
+            for distance_m in [10.0, 100.0, 1_000.0, 9_000.0] {
+                let depth = depth_mapping.depth(distance_m);
+                assert!((0.0..=1.0).contains(&depth), "{:?}: {}", mode, depth);
+                let distance_back_m = depth_mapping.distance_m(depth).unwrap();
+                assert!(
+                    (distance_back_m - distance_m).abs() < 1e-4 * distance_m,
+                    "{:?}: {} m is {} m after the depth {}",
+                    mode,
+                    distance_m,
+                    distance_back_m,
+                    depth
+                );
+                // Closer surfaces have to win the depth test of the pipeline
+                if let Some(previous_depth) = previous_depth {
+                    assert_eq!(depth_mapping.is_reversed(), depth < previous_depth);
+                }
+                previous_depth = Some(depth);
+            }
+        }
+    }
+
+    #[test]
+    fn view_range_is_not_clipped() {
+        for mode in [
+            DepthMode::Linear,
+            DepthMode::ReverseZ,
+            DepthMode::Logarithmic,
+        ] {
+            for view_range_m in [5_000.0, 50_000.0, 200_000.0] {
+                let depth_mapping = DepthMapping::new(mode, 500.0, view_range_m);
+                let depth = depth_mapping.depth(view_range_m);
+                assert!(
+                    (0.0..=1.0).contains(&depth) && depth != depth_mapping.sky_depth(),
+                    "{:?}: {} m has the depth {}",
+                    mode,
+                    view_range_m,
+                    depth
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn far_plane_is_sky() {
+        for mode in [
+            DepthMode::Linear,
+            DepthMode::ReverseZ,
+            DepthMode::Logarithmic,
+        ] {
+            let depth_mapping = DepthMapping::new(mode, 500.0, 20_000.0);
+            let far_depth = depth_mapping.depth(depth_mapping.far_m);
+            assert!((far_depth - depth_mapping.sky_depth()).abs() < 1e-6);
+            assert_eq!(depth_mapping.distance_m(depth_mapping.sky_depth()), None);
+        }
+    }
+}
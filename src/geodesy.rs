@@ -0,0 +1,272 @@
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4};
+
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::Coords;
+
+/// Semi-major axis and first eccentricity squared of the WGS84 ellipsoid
+const WGS84_A_M: f64 = 6_378_137.0;
+const WGS84_E2: f64 = 0.006_694_379_990_141_317;
+/// Semi-major axis and first eccentricity squared of the Bessel 1841 ellipsoid of CH1903+
+const BESSEL_A_M: f64 = 6_377_397.155;
+const BESSEL_E2: f64 = 0.006_674_372_230_614;
+/// Position of the CH1903+ geocentric origin in WGS84 geocentric coordinates
+const CH1903_TO_WGS84_M: [f64; 3] = [674.374, 15.056, 405.346];
+/// Projection center of the Swiss oblique Mercator projection, the old observatory of Bern
+const BERN_LATITUDE_DEG: f64 = 46.952_405_555_555_56;
+const BERN_LONGITUDE_DEG: f64 = 7.439_583_333_333_333;
+/// Mean radius of the earth
+pub const EARTH_RADIUS_M: f32 = 6_371_000.0;
+/// Coefficient of terrestrial refraction of a standard atmosphere
+pub const STANDARD_REFRACTION_COEFFICIENT: f32 = 0.13;
+/// False easting and northing of LV95 and LV03
+const LV95_OFFSET_M: [f64; 2] = [2_600_000.0, 1_200_000.0];
+const LV03_OFFSET_M: [f64; 2] = [600_000.0, 200_000.0];
+
+/// Geographic coordinates on the WGS84 ellipsoid
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub struct Wgs84 {
+    pub latitude_deg: f64,
+    pub longitude_deg: f64,
+    /// Height above the ellipsoid
+    pub height_m: f64,
+}
+
+impl Wgs84 {
+    /// Converts LV95 coordinates, see `Wgs84::to_lv95` for the height
+    pub fn from_lv95(lv95: Point3<f64>) -> Self {
+        let (latitude, longitude) =
+            SwissProjection::new().inverse(lv95.x - LV95_OFFSET_M[0], lv95.y - LV95_OFFSET_M[1]);
+        let wgs84 = geodetic_to_geocentric(BESSEL_A_M, BESSEL_E2, latitude, longitude, lv95.z)
+            + Vector3::from(CH1903_TO_WGS84_M);
+        let (latitude, longitude, height_m) = geocentric_to_geodetic(WGS84_A_M, WGS84_E2, &wgs84);
+        Wgs84 {
+            latitude_deg: latitude.to_degrees(),
+            longitude_deg: longitude.to_degrees(),
+            height_m,
+        }
+    }
+
+    /// Converts to LV95 with the rigorous formulas of swisstopo
+    ///
+    /// The datum is shifted geocentrically to CH1903+ and the Bessel ellipsoid is projected with
+    /// the Swiss oblique Mercator projection. The height is the one above the Bessel ellipsoid,
+    /// which differs from the LHN95 heights of the terrain models by a few meters at most.
+    pub fn to_lv95(&self) -> Point3<f64> {
+        let ch1903 = geodetic_to_geocentric(
+            WGS84_A_M,
+            WGS84_E2,
+            self.latitude_deg.to_radians(),
+            self.longitude_deg.to_radians(),
+            self.height_m,
+        ) - Vector3::from(CH1903_TO_WGS84_M);
+        let (latitude, longitude, height_m) =
+            geocentric_to_geodetic(BESSEL_A_M, BESSEL_E2, &ch1903);
+        let (easting_m, northing_m) = SwissProjection::new().forward(latitude, longitude);
+        Point3::new(
+            easting_m + LV95_OFFSET_M[0],
+            northing_m + LV95_OFFSET_M[1],
+            height_m,
+        )
+    }
+}
+
+impl From<Coords> for Wgs84 {
+    fn from(coords: Coords) -> Self {
+        Wgs84::from_lv95(coords)
+    }
+}
+
+impl From<Wgs84> for Coords {
+    fn from(wgs84: Wgs84) -> Self {
+        wgs84.to_lv95()
+    }
+}
+
+/// Apparent drop of the terrain at a horizontal distance due to earth curvature
+///
+/// Refraction bends the lines of sight towards the earth, which is modeled as an earth radius
+/// enlarged by 1 / (1 - refraction_coefficient).
+pub fn curvature_drop_m(distance_m: f32, refraction_coefficient: f32) -> f32 {
+    distance_m.powi(2) * (1.0 - refraction_coefficient) / (2.0 * EARTH_RADIUS_M)
+}
+
+/// Converts LV03 coordinates to LV95, ignoring the local distortions of LV03 of up to 1.6m
+pub fn lv03_to_lv95(lv03: Point3<f64>) -> Point3<f64> {
+    Point3::new(
+        lv03.x - LV03_OFFSET_M[0] + LV95_OFFSET_M[0],
+        lv03.y - LV03_OFFSET_M[1] + LV95_OFFSET_M[1],
+        lv03.z,
+    )
+}
+
+/// Converts LV95 coordinates to LV03, ignoring the local distortions of LV03 of up to 1.6m
+pub fn lv95_to_lv03(lv95: Point3<f64>) -> Point3<f64> {
+    Point3::new(
+        lv95.x - LV95_OFFSET_M[0] + LV03_OFFSET_M[0],
+        lv95.y - LV95_OFFSET_M[1] + LV03_OFFSET_M[1],
+        lv95.z,
+    )
+}
+
+fn geodetic_to_geocentric(
+    a_m: f64,
+    e2: f64,
+    latitude: f64,
+    longitude: f64,
+    height_m: f64,
+) -> Point3<f64> {
+    let n = a_m / (1.0 - e2 * latitude.sin().powi(2)).sqrt();
+    Point3::new(
+        (n + height_m) * latitude.cos() * longitude.cos(),
+        (n + height_m) * latitude.cos() * longitude.sin(),
+        (n * (1.0 - e2) + height_m) * latitude.sin(),
+    )
+}
+
+/// Latitude, longitude and height of a geocentric position
+fn geocentric_to_geodetic(a_m: f64, e2: f64, geocentric: &Point3<f64>) -> (f64, f64, f64) {
+    let longitude = geocentric.y.atan2(geocentric.x);
+    let p = geocentric.xy().coords.norm();
+    let mut latitude = geocentric.z.atan2(p * (1.0 - e2));
+    let mut height_m = 0.0;
+    // Converges to below a micrometer in a few iterations for heights on earth
+    for _ in 0..10 {
+        let n = a_m / (1.0 - e2 * latitude.sin().powi(2)).sqrt();
+        height_m = p / latitude.cos() - n;
+        latitude = geocentric.z.atan2(p * (1.0 - e2 * n / (n + height_m)));
+    }
+    (latitude, longitude, height_m)
+}
+
+/// Constants of the Swiss oblique Mercator projection of the Bessel ellipsoid
+///
+/// The ellipsoid is mapped conformally to a sphere, which is rotated to put Bern on the equator
+/// and projected with a Mercator projection. Coordinates are relative to Bern.
+struct SwissProjection {
+    /// Radius of the projection sphere
+    radius_m: f64,
+    alpha: f64,
+    /// Latitude of Bern on the sphere
+    b0: f64,
+    k: f64,
+    e: f64,
+    longitude0: f64,
+}
+
+impl SwissProjection {
+    fn new() -> Self {
+        let e = BESSEL_E2.sqrt();
+        let latitude0 = BERN_LATITUDE_DEG.to_radians();
+        let sin0 = latitude0.sin();
+        let radius_m = BESSEL_A_M * (1.0 - BESSEL_E2).sqrt() / (1.0 - BESSEL_E2 * sin0.powi(2));
+        let alpha = (1.0 + BESSEL_E2 / (1.0 - BESSEL_E2) * latitude0.cos().powi(4)).sqrt();
+        let b0 = (sin0 / alpha).asin();
+        let k = (FRAC_PI_4 + b0 / 2.0).tan().ln()
+            - alpha * (FRAC_PI_4 + latitude0 / 2.0).tan().ln()
+            + alpha * e / 2.0 * ((1.0 + e * sin0) / (1.0 - e * sin0)).ln();
+        SwissProjection {
+            radius_m,
+            alpha,
+            b0,
+            k,
+            e,
+            longitude0: BERN_LONGITUDE_DEG.to_radians(),
+        }
+    }
+
+    /// Easting and northing relative to Bern of a position on the Bessel ellipsoid
+    fn forward(&self, latitude: f64, longitude: f64) -> (f64, f64) {
+        let e = self.e;
+        let sin = latitude.sin();
+        let s = self.alpha * (FRAC_PI_4 + latitude / 2.0).tan().ln()
+            - self.alpha * e / 2.0 * ((1.0 + e * sin) / (1.0 - e * sin)).ln()
+            + self.k;
+        let b = 2.0 * (s.exp().atan() - FRAC_PI_4);
+        let l = self.alpha * (longitude - self.longitude0);
+        let l_rot = l
+            .sin()
+            .atan2(self.b0.sin() * b.tan() + self.b0.cos() * l.cos());
+        let b_rot = (self.b0.cos() * b.sin() - self.b0.sin() * b.cos() * l.cos()).asin();
+        (
+            self.radius_m * l_rot,
+            self.radius_m / 2.0 * ((1.0 + b_rot.sin()) / (1.0 - b_rot.sin())).ln(),
+        )
+    }
+
+    /// Latitude and longitude on the Bessel ellipsoid of a position relative to Bern
+    fn inverse(&self, easting_m: f64, northing_m: f64) -> (f64, f64) {
+        let e = self.e;
+        let l_rot = easting_m / self.radius_m;
+        let b_rot = 2.0 * ((northing_m / self.radius_m).exp().atan() - FRAC_PI_4);
+        let b = (self.b0.cos() * b_rot.sin() + self.b0.sin() * b_rot.cos() * l_rot.cos()).asin();
+        let l = l_rot
+            .sin()
+            .atan2(self.b0.cos() * l_rot.cos() - self.b0.sin() * b_rot.tan());
+        let longitude = self.longitude0 + l / self.alpha;
+        let mut latitude = b;
+        for _ in 0..10 {
+            let s = ((FRAC_PI_4 + b / 2.0).tan().ln() - self.k) / self.alpha
+                + e * (FRAC_PI_4 + (e * latitude.sin()).asin() / 2.0).tan().ln();
+            latitude = 2.0 * s.exp().atan() - FRAC_PI_2;
+        }
+        (latitude, longitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Degrees of an angle in degrees, minutes and seconds
+    fn dms(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+        degrees + minutes / 60.0 + seconds / 3600.0
+    }
+
+    #[test]
+    fn swisstopo_reference_point() {
+        // Example of the swisstopo documentation of the transformation, rounded to 0.01"
+        let lv95 = Point3::new(2_700_000.0, 1_100_000.0, 600.0);
+        let latitude_deg = dms(46.0, 2.0, 38.87);
+        let longitude_deg = dms(8.0, 43.0, 49.79);
+
+        let wgs84 = Wgs84::from_lv95(lv95);
+        assert!((wgs84.latitude_deg - latitude_deg).abs() < dms(0.0, 0.0, 0.01));
+        assert!((wgs84.longitude_deg - longitude_deg).abs() < dms(0.0, 0.0, 0.01));
+
+        let wgs84 = Wgs84 {
+            latitude_deg,
+            longitude_deg,
+            height_m: wgs84.height_m,
+        };
+        let projected = wgs84.to_lv95();
+        assert!((projected.xy() - lv95.xy()).norm() < 0.5, "{}", projected);
+    }
+
+    #[test]
+    fn projection_center_is_bern() {
+        // The old observatory of Bern in WGS84
+        let wgs84 = Wgs84::from_lv95(Point3::new(2_600_000.0, 1_200_000.0, 0.0));
+        assert!((wgs84.latitude_deg - dms(46.0, 57.0, 3.90)).abs() < dms(0.0, 0.0, 0.01));
+        assert!((wgs84.longitude_deg - dms(7.0, 26.0, 19.08)).abs() < dms(0.0, 0.0, 0.01));
+    }
+
+    #[test]
+    fn lv95_round_trip() {
+        for lv95 in [
+            Point3::new(2_600_000.0, 1_200_000.0, 0.0),
+            Point3::new(2_485_000.0, 1_110_000.0, 400.0),
+            Point3::new(2_834_000.0, 1_296_000.0, 4_000.0),
+        ] {
+            let round_trip = Wgs84::from_lv95(lv95).to_lv95();
+            assert!(
+                (round_trip - lv95).norm() < 1e-3,
+                "{} is {}",
+                lv95,
+                round_trip
+            );
+            assert_eq!(lv03_to_lv95(lv95_to_lv03(lv95)), lv95);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Result};
+use nalgebra::Point3;
+use tiff::decoder::DecodingResult;
+use tiff::tags::{PlanarConfiguration, Tag};
+
+use crate::geodesy::Wgs84;
+use crate::Coords;
+
+/// GeoKey of the raster type and its value for grids referencing pixel centers
+const GT_RASTER_TYPE_GEO_KEY: u16 = 1025;
+const RASTER_PIXEL_IS_POINT: u16 = 2;
+
+/// Grid of geoid undulations, the height of the geoid above the ellipsoid
+///
+/// The grid is a single band float GeoTIFF in latitude and longitude degrees, such as the
+/// CHGeo2004 grids distributed with PROJ. Ellipsoidal heights relative to WGS84 are converted to
+/// the orthometric heights of the swisstopo elevation models by subtracting the undulation.
+#[derive(Debug, Clone)]
+pub struct Geoid {
+    width: usize,
+    height: usize,
+    /// Longitude and latitude of the center of the top left sample
+    origin_deg: [f64; 2],
+    /// Spacing of the samples in longitude and latitude, positive to the east and south
+    spacing_deg: [f64; 2],
+    undulation_m: Vec<f32>,
+}
+
+impl Geoid {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut decoder = tiff::decoder::Decoder::new(File::open(path)?)?;
+        let (width, height) = decoder.dimensions()?;
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+        ensure!(
+            scale.len() >= 2 && tiepoint.len() >= 6,
+            "Geoid grid is not georeferenced"
+        );
+        let pixel_is_point = decoder
+            .find_tag_unsigned_vec::<u16>(Tag::GeoKeyDirectoryTag)?
+            .map(|keys| {
+                keys.chunks_exact(4)
+                    .skip(1)
+                    .any(|key| key[0] == GT_RASTER_TYPE_GEO_KEY && key[3] == RASTER_PIXEL_IS_POINT)
+            })
+            .unwrap_or(false);
+        // Area rasters reference the corner of the pixel, the samples are at the pixel centers
+        let center_offset = if pixel_is_point { 0.0 } else { 0.5 };
+        let planar = decoder
+            .find_tag_unsigned(Tag::PlanarConfiguration)?
+            .and_then(PlanarConfiguration::from_u16)
+            == Some(PlanarConfiguration::Planar);
+        let undulation_m = match decoder.read_image()? {
+            DecodingResult::F32(pixels) => pixels,
+            DecodingResult::F64(pixels) => pixels.into_iter().map(|x| x as f32).collect(),
+            _ => bail!("Geoid grid is not float"),
+        };
+        // Only the first band is used if there are several, which have to be interleaved
+        let bands = undulation_m.len() / (width * height) as usize;
+        ensure!(bands > 0, "Geoid grid is empty");
+        ensure!(
+            bands == 1 || !planar,
+            "Geoid grid has {} bands in separate planes, convert it to a single band",
+            bands
+        );
+        Ok(Geoid {
+            width: width as usize,
+            height: height as usize,
+            origin_deg: [
+                tiepoint[3] + (center_offset - tiepoint[0]) * scale[0],
+                tiepoint[4] - (center_offset - tiepoint[1]) * scale[1],
+            ],
+            spacing_deg: [scale[0], -scale[1]],
+            undulation_m: undulation_m.into_iter().step_by(bands).collect(),
+        })
+    }
+
+    /// Bilinearly interpolated undulation at a WGS84 position
+    pub fn undulation_m(&self, latitude_deg: f64, longitude_deg: f64) -> Result<f64> {
+        let x = (longitude_deg - self.origin_deg[0]) / self.spacing_deg[0];
+        let y = (latitude_deg - self.origin_deg[1]) / self.spacing_deg[1];
+        ensure!(
+            x >= 0.0 && y >= 0.0 && x <= (self.width - 1) as f64 && y <= (self.height - 1) as f64,
+            "Position {}, {} is outside of the geoid grid",
+            latitude_deg,
+            longitude_deg
+        );
+        let (x0, y0) = (
+            (x.floor() as usize).min(self.width.saturating_sub(2)),
+            (y.floor() as usize).min(self.height.saturating_sub(2)),
+        );
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+        let sample = |x: usize, y: usize| self.undulation_m[y * self.width + x] as f64;
+        // Samples without weight are skipped, so missing samples only spoil the cells around them
+        let lerp = |a: f64, b: f64, f: f64| {
+            if f == 0.0 {
+                a
+            } else if f == 1.0 {
+                b
+            } else {
+                a * (1.0 - f) + b * f
+            }
+        };
+        let top = lerp(sample(x0, y0), sample(x1, y0), fx);
+        let bottom = lerp(sample(x0, y1), sample(x1, y1), fx);
+        let undulation_m = lerp(top, bottom, fy);
+        ensure!(
+            undulation_m.is_finite(),
+            "No geoid undulation at {}, {}",
+            latitude_deg,
+            longitude_deg
+        );
+        Ok(undulation_m)
+    }
+
+    /// LV95 position with the orthometric height of a WGS84 position
+    pub fn to_lv95(&self, wgs84: &Wgs84) -> Result<Point3<f64>> {
+        let mut lv95 = wgs84.to_lv95();
+        lv95.z = wgs84.height_m - self.undulation_m(wgs84.latitude_deg, wgs84.longitude_deg)?;
+        Ok(lv95)
+    }
+
+    /// WGS84 position with the ellipsoidal height of an LV95 position with orthometric height
+    pub fn to_wgs84(&self, lv95: Point3<f64>) -> Result<Wgs84> {
+        let mut wgs84 = Wgs84::from_lv95(lv95);
+        wgs84.height_m = lv95.z + self.undulation_m(wgs84.latitude_deg, wgs84.longitude_deg)?;
+        Ok(wgs84)
+    }
+}
+
+/// Converts a WGS84 position to LV95, with orthometric heights if a geoid is given
+///
+/// Without a geoid the height above the Bessel ellipsoid is used, see `Wgs84::to_lv95`.
+pub fn wgs84_to_lv95(wgs84: &Wgs84, geoid: Option<&Geoid>) -> Result<Coords> {
+    match geoid {
+        Some(geoid) => geoid.to_lv95(wgs84),
+        None => Ok(wgs84.to_lv95()),
+    }
+}
+
+/// Converts an LV95 position to WGS84, with ellipsoidal heights if a geoid is given
+pub fn lv95_to_wgs84(lv95: Coords, geoid: Option<&Geoid>) -> Result<Wgs84> {
+    match geoid {
+        Some(geoid) => geoid.to_wgs84(lv95),
+        None => Ok(Wgs84::from_lv95(lv95)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Grid of 3 x 2 samples 0.5 degrees apart with its top left sample at 47.5 N, 6 E
+    fn geoid() -> Geoid {
+        Geoid {
+            width: 3,
+            height: 2,
+            origin_deg: [6.0, 47.5],
+            spacing_deg: [0.5, -0.5],
+            undulation_m: vec![50.0, 51.0, 52.0, 48.0, 49.0, f32::NAN],
+        }
+    }
+
+    #[test]
+    fn bilinear_interpolation() {
+        let geoid = geoid();
+        // Samples are exact at the grid points
+        assert_eq!(geoid.undulation_m(47.5, 6.0).unwrap(), 50.0);
+        assert_eq!(geoid.undulation_m(47.0, 6.5).unwrap(), 49.0);
+        // Linear along the edges and bilinear inside a cell
+        assert!((geoid.undulation_m(47.5, 6.25).unwrap() - 50.5).abs() < 1e-9);
+        assert!((geoid.undulation_m(47.25, 6.0).unwrap() - 49.0).abs() < 1e-9);
+        assert!((geoid.undulation_m(47.375, 6.125).unwrap() - 49.75).abs() < 1e-9);
+        assert!((geoid.undulation_m(47.25, 6.25).unwrap() - 49.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn outside_and_missing_samples_fail() {
+        let geoid = geoid();
+        assert!(geoid.undulation_m(47.6, 6.0).is_err());
+        assert!(geoid.undulation_m(47.0, 5.9).is_err());
+        assert!(geoid.undulation_m(46.9, 6.0).is_err());
+        assert!(geoid.undulation_m(47.25, 6.75).is_err());
+    }
+
+    #[test]
+    fn conversions_at_missing_samples_fail() {
+        let geoid = geoid();
+        let valid = Wgs84 {
+            latitude_deg: 47.25,
+            longitude_deg: 6.25,
+            height_m: 1000.0,
+        };
+        let lv95 = geoid.to_lv95(&valid).unwrap();
+        assert!((lv95.z - 950.5).abs() < 1e-9);
+        let missing = Wgs84 {
+            longitude_deg: 6.75,
+            ..valid
+        };
+        assert!(geoid.to_lv95(&missing).is_err());
+        assert!(wgs84_to_lv95(&missing, Some(&geoid)).is_err());
+        assert!(wgs84_to_lv95(&missing, None).is_ok());
+    }
+}
@@ -1,15 +1,17 @@
 use std::convert::TryInto;
 use std::fs::File;
 use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
-use image::{imageops::FilterType, ImageBuffer};
-use log::debug;
+use image::{imageops::FilterType, GrayImage, ImageBuffer, Luma};
+use log::{debug, warn};
 use nalgebra::{Point2, Vector3};
 use tiff::decoder::DecodingResult;
 use wgpu::util::DeviceExt;
 
-use crate::config::StorageConfig;
+use crate::config::{ElevationSource, StorageConfig};
+use crate::landcover;
 use crate::model::{Material, Mesh, Model, ModelVertex};
 use crate::texture::Texture;
 use crate::Coords;
@@ -21,6 +23,11 @@ const ELEVATION_MAX_LOD: usize = 2;
 const ORTHOIMAGE_MAX_LOD: usize = 5;
 const MESH_MAX_RESOLUTION: u32 = 4000;
 const MESH_MIN_RESOLUTION: u32 = 2; //60;
+/// Side length of the label texture of a tile, 1m per pixel
+const LABEL_RESOLUTION_PX: u32 = 1000;
+
+/// Index of the label material in the models of tiles loaded with labels
+pub const LABEL_MATERIAL: usize = 1;
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct GridCoords(pub Point2<i32>);
@@ -28,8 +35,8 @@ pub struct GridCoords(pub Point2<i32>);
 impl From<Coords> for GridCoords {
     fn from(point: Coords) -> Self {
         Self(Point2::new(
-            (point.x / IMAGE_SIZE_M).floor() as i32,
-            (point.y / IMAGE_SIZE_M).floor() as i32,
+            (point.x / IMAGE_SIZE_M as f64).floor() as i32,
+            (point.y / IMAGE_SIZE_M as f64).floor() as i32,
         ))
     }
 }
@@ -37,8 +44,8 @@ impl From<Coords> for GridCoords {
 impl From<GridCoords> for Coords {
     fn from(coords: GridCoords) -> Self {
         Coords::new(
-            coords.0.x as f32 * IMAGE_SIZE_M,
-            coords.0.y as f32 * IMAGE_SIZE_M,
+            coords.0.x as f64 * IMAGE_SIZE_M as f64,
+            coords.0.y as f64 * IMAGE_SIZE_M as f64,
             0.0,
         )
     }
@@ -106,6 +113,8 @@ pub struct GridSquare {
     pub coords: GridCoords,
     /// Grid with elevation data for each vertex
     pub elevation: ndarray::Array2<f32>,
+    /// Height of the surface above the bare terrain for each vertex, if loaded
+    pub object_height: Option<ndarray::Array2<f32>>,
     /// Paths for swisstopo data
     pub storage_config: StorageConfig,
 }
@@ -118,26 +127,80 @@ impl GridSquare {
         storage_config: StorageConfig,
     ) -> Result<GridSquare> {
         let resolution = ((IMAGE_SIZE_M / resolution_m).ceil() as u32).max(2);
-        let mut path = storage_config
-            .surface_dir
-            .join(format!("{}-{}.tif", coords.0.x, coords.0.y));
-        if !path.exists() {
-            path = storage_config
-                .alti_dir
-                .join(format!("{}-{}.tif", coords.0.x, coords.0.y))
-        }
-        let image = File::open(path)?;
-        let mut decoder = tiff::decoder::Decoder::new(image)?;
+        let path = Self::elevation_path(coords, &storage_config);
 
         debug!(
             "Loading tile {:?} at resolution {}m -> {}",
             coords, resolution_m, resolution
         );
-        let mesh_resolution = resolution.min(MESH_MAX_RESOLUTION).max(MESH_MIN_RESOLUTION) as usize;
+        let elevation = Self::load_elevation(&path, coords, resolution, None)?;
+
+        Ok(GridSquare {
+            resolution,
+            coords,
+            elevation,
+            object_height: None,
+            storage_config,
+        })
+    }
+
+    /// Elevation model tif of a tile according to the elevation source of the storage config
+    fn elevation_path(coords: GridCoords, storage_config: &StorageConfig) -> PathBuf {
+        let file_name = format!("{}-{}.tif", coords.0.x, coords.0.y);
+        let surface_path = storage_config.surface_dir.join(&file_name);
+        match storage_config.elevation_source {
+            ElevationSource::Surface => surface_path,
+            ElevationSource::Terrain => storage_config.alti_dir.join(&file_name),
+            ElevationSource::SurfaceWithFallback if surface_path.exists() => surface_path,
+            ElevationSource::SurfaceWithFallback => storage_config.alti_dir.join(&file_name),
+        }
+    }
+
+    /// Load the bare terrain of the tile and keep the height of the surface above it
+    ///
+    /// Heights are clamped to zero where the terrain model lies above the surface model. Tiles
+    /// loaded from the terrain model, because of the elevation source or a missing surface
+    /// model, get zero heights.
+    pub fn load_object_height(&mut self) -> Result<()> {
+        let alti_path = self
+            .storage_config
+            .alti_dir
+            .join(format!("{}-{}.tif", self.coords.0.x, self.coords.0.y));
+        let mesh_resolution = self.elevation.dim().0 - 1;
+        if Self::elevation_path(self.coords, &self.storage_config) == alti_path {
+            self.object_height = Some(ndarray::Array2::zeros(self.elevation.dim()));
+            return Ok(());
+        }
+        let ground = Self::load_elevation(
+            &alti_path,
+            self.coords,
+            self.resolution,
+            Some(mesh_resolution),
+        )?;
+        self.object_height = Some((&self.elevation - &ground).mapv(|height| height.max(0.0)));
+        Ok(())
+    }
+
+    /// Elevation of each vertex of a tile from an elevation model tif
+    ///
+    /// The mesh resolution is derived from the resolution and the tif unless it is given, the
+    /// grid has one more vertex than the mesh resolution per side.
+    fn load_elevation(
+        path: &Path,
+        coords: GridCoords,
+        resolution: u32,
+        mesh_resolution: Option<usize>,
+    ) -> Result<ndarray::Array2<f32>> {
+        let image = File::open(path)?;
+        let mut decoder = tiff::decoder::Decoder::new(image)?;
 
-        let lod = calc_lod(decoder.dimensions()?.0, mesh_resolution as u32);
+        let target_resolution = mesh_resolution
+            .unwrap_or(resolution.min(MESH_MAX_RESOLUTION).max(MESH_MIN_RESOLUTION) as usize);
+
+        let lod = calc_lod(decoder.dimensions()?.0, target_resolution as u32);
         // Make sure meshes are similar resolutions to allow good matching with neighboring squares
-        let mesh_resolution = (decoder.dimensions()?.0 / (1 << lod)).next_power_of_two() as usize;
+        let mesh_resolution = mesh_resolution
+            .unwrap_or((decoder.dimensions()?.0 / (1 << lod)).next_power_of_two() as usize);
 
         debug!("Mesh is {}x{}", mesh_resolution, mesh_resolution);
 
@@ -184,13 +247,7 @@ impl GridSquare {
             elevation[[mesh_resolution, y]] =
                 elevation[[mesh_resolution - 1, y.min(mesh_resolution - 1)]];
         }
-
-        Ok(GridSquare {
-            resolution,
-            coords,
-            elevation,
-            storage_config,
-        })
+        Ok(elevation)
     }
 
     /// Fill in the border of the elevation grid to match with neighboring cells
@@ -211,8 +268,9 @@ impl GridSquare {
                         x as f32 / mesh_resolution as f32 * IMAGE_SIZE_M,
                         IMAGE_SIZE_M,
                         0f32,
-                    );
-                self.elevation[[x, mesh_resolution]] = bottom_neighbor.sample_altitude(coords);
+                    )
+                    .cast();
+                self.copy_border_vertex([x, mesh_resolution], bottom_neighbor, coords);
             }
         }
         // Fill rightmost row
@@ -223,32 +281,50 @@ impl GridSquare {
                         IMAGE_SIZE_M,
                         y as f32 / mesh_resolution as f32 * IMAGE_SIZE_M,
                         0f32,
-                    );
-                self.elevation[[mesh_resolution, y]] = right_neighbor.sample_altitude(coords);
+                    )
+                    .cast();
+                self.copy_border_vertex([mesh_resolution, y], right_neighbor, coords);
             }
         }
         // Fill top row
         if let Some(top_neighbor) = top_neighbor {
             for x in 0..mesh_resolution + 1 {
                 let coords: Coords = origin
-                    + Vector3::new(x as f32 / mesh_resolution as f32 * IMAGE_SIZE_M, 0f32, 0f32);
-                self.elevation[[x, 0]] = top_neighbor.sample_altitude(coords);
+                    + Vector3::new(x as f32 / mesh_resolution as f32 * IMAGE_SIZE_M, 0f32, 0f32)
+                        .cast();
+                self.copy_border_vertex([x, 0], top_neighbor, coords);
             }
         }
         // Fill leftmost row
         if let Some(left_neighbor) = left_neighbor {
             for y in 0..mesh_resolution + 1 {
                 let coords: Coords = origin
-                    + Vector3::new(0f32, y as f32 / mesh_resolution as f32 * IMAGE_SIZE_M, 0f32);
-                self.elevation[[0, y]] = left_neighbor.sample_altitude(coords);
+                    + Vector3::new(0f32, y as f32 / mesh_resolution as f32 * IMAGE_SIZE_M, 0f32)
+                        .cast();
+                self.copy_border_vertex([0, y], left_neighbor, coords);
             }
         }
     }
 
+    /// Take the elevation and object height of a border vertex from the neighboring tile
+    fn copy_border_vertex(&mut self, index: [usize; 2], neighbor: &GridSquare, coords: Coords) {
+        self.elevation[index] = neighbor.sample_altitude(coords);
+        if let (Some(object_height), Some(neighbor_object_height)) =
+            (&mut self.object_height, &neighbor.object_height)
+        {
+            object_height[index] = neighbor.sample_grid(neighbor_object_height, coords);
+        }
+    }
+
     /// Bilinearly interpolated sampling of the altitude mesh
     pub fn sample_altitude(&self, coords: Coords) -> f32 {
+        self.sample_grid(&self.elevation, coords)
+    }
+
+    /// Bilinearly interpolated sampling of a grid with one value per vertex
+    fn sample_grid(&self, grid: &ndarray::Array2<f32>, coords: Coords) -> f32 {
         let origin: Coords = self.coords.into();
-        let idx = (self.elevation.dim().0 - 1) as f32 * (coords - origin) / IMAGE_SIZE_M;
+        let idx = (grid.dim().0 - 1) as f32 * (coords - origin).cast::<f32>() / IMAGE_SIZE_M;
         let left = idx.x.floor() as usize;
         let right = left + 1;
         let left_fac = right as f32 - idx.x;
@@ -257,30 +333,48 @@ impl GridSquare {
         let top = bottom + 1;
         let bottom_fac = top as f32 - idx.y;
         let top_fac = idx.y - bottom as f32;
-        let left = left.min(self.elevation.dim().0 - 1);
-        let right = right.min(self.elevation.dim().0 - 1);
-        let bottom = bottom.min(self.elevation.dim().0 - 1);
-        let top = top.min(self.elevation.dim().0 - 1);
-        let left_val =
-            self.elevation[[left, top]] * top_fac + self.elevation[[left, bottom]] * bottom_fac;
-        let right_val =
-            self.elevation[[right, top]] * top_fac + self.elevation[[right, bottom]] * bottom_fac;
+        let left = left.min(grid.dim().0 - 1);
+        let right = right.min(grid.dim().0 - 1);
+        let bottom = bottom.min(grid.dim().0 - 1);
+        let top = top.min(grid.dim().0 - 1);
+        let left_val = grid[[left, top]] * top_fac + grid[[left, bottom]] * bottom_fac;
+        let right_val = grid[[right, top]] * top_fac + grid[[right, bottom]] * bottom_fac;
         left_val * left_fac + right_val * right_fac
     }
 
-    pub fn mesh(&self, device: &wgpu::Device) -> Mesh {
+    /// Unit normal of the elevation grid at a vertex, from central differences inside the grid
+    fn normal(&self, x: usize, y: usize, grid_size_m: f32) -> [f32; 3] {
+        let last = self.elevation.dim().0 - 1;
+        let (left, right) = (x.saturating_sub(1), (x + 1).min(last));
+        let (bottom, top) = (y.saturating_sub(1), (y + 1).min(last));
+        let slope_x = (self.elevation[[right, y]] - self.elevation[[left, y]])
+            / ((right - left) as f32 * grid_size_m);
+        let slope_y = (self.elevation[[x, top]] - self.elevation[[x, bottom]])
+            / ((top - bottom) as f32 * grid_size_m);
+        Vector3::new(-slope_x, -slope_y, 1.0).normalize().into()
+    }
+
+    /// Mesh with vertex positions relative to the given origin
+    pub fn mesh(&self, device: &wgpu::Device, origin: Coords) -> Mesh {
         let mut vertices: Vec<ModelVertex> = Vec::new();
         let mut indices: Vec<u32> = Vec::new();
         let resolution = self.elevation.len_of(ndarray::Axis(0));
         let inv_resolution = 1f32 / (resolution - 1) as f32;
         let grid_size_m = IMAGE_SIZE_M * inv_resolution;
-        let origin: Coords = self.coords.into();
+        // The tile corner is exact in f64, only the offset to the origin is rounded
+        let corner: Coords = self.coords.into();
+        let offset = (corner - origin).cast::<f32>();
+        let object_height = |index: [usize; 2]| {
+            self.object_height
+                .as_ref()
+                .map_or(0.0, |object_height| object_height[index])
+        };
         for x in 0..resolution - 1 {
             for y in 0..resolution - 1 {
-                let x0 = x as f32 * grid_size_m + origin.x;
-                let x1 = (x + 1) as f32 * grid_size_m + origin.x;
-                let y0 = y as f32 * grid_size_m + origin.y;
-                let y1 = (y + 1) as f32 * grid_size_m + origin.y;
+                let x0 = x as f32 * grid_size_m + offset.x;
+                let x1 = (x + 1) as f32 * grid_size_m + offset.x;
+                let y0 = y as f32 * grid_size_m + offset.y;
+                let y1 = (y + 1) as f32 * grid_size_m + offset.y;
                 let u0 = x as f32 * inv_resolution;
                 let v0 = 1.0 - y as f32 * inv_resolution;
                 let u1 = (x + 1) as f32 * inv_resolution;
@@ -288,20 +382,28 @@ impl GridSquare {
                 let v = vertices.len() as u32;
                 indices.append(&mut vec![v + 2, v + 1, v, v + 1, v + 2, v + 3]);
                 vertices.push(ModelVertex {
-                    position: [x0, y0, self.elevation[[x, y]]],
+                    position: [x0, y0, self.elevation[[x, y]] + offset.z],
                     tex_coords: [u0, v0],
+                    normal: self.normal(x, y, grid_size_m),
+                    object_height_m: object_height([x, y]),
                 });
                 vertices.push(ModelVertex {
-                    position: [x0, y1, self.elevation[[x, y + 1]]],
+                    position: [x0, y1, self.elevation[[x, y + 1]] + offset.z],
                     tex_coords: [u0, v1],
+                    normal: self.normal(x, y + 1, grid_size_m),
+                    object_height_m: object_height([x, y + 1]),
                 });
                 vertices.push(ModelVertex {
-                    position: [x1, y0, self.elevation[[x + 1, y]]],
+                    position: [x1, y0, self.elevation[[x + 1, y]] + offset.z],
                     tex_coords: [u1, v0],
+                    normal: self.normal(x + 1, y, grid_size_m),
+                    object_height_m: object_height([x + 1, y]),
                 });
                 vertices.push(ModelVertex {
-                    position: [x1, y1, self.elevation[[x + 1, y + 1]]],
+                    position: [x1, y1, self.elevation[[x + 1, y + 1]] + offset.z],
                     tex_coords: [u1, v1],
+                    normal: self.normal(x + 1, y + 1, grid_size_m),
+                    object_height_m: object_height([x + 1, y + 1]),
                 });
             }
         }
@@ -327,11 +429,37 @@ impl GridSquare {
         }
     }
 
+    /// Class ids of the land cover of the tile, see landcover::rasterize_tile
+    ///
+    /// Tiles without a land cover file are LABEL_OTHER.
+    pub fn labels(&self) -> Result<GrayImage> {
+        let landcover_dir = self
+            .storage_config
+            .landcover_dir
+            .as_ref()
+            .context("No land cover dir to load labels from")?;
+        let path = landcover_dir.join(format!("{}-{}.geojson", self.coords.0.x, self.coords.0.y));
+        if !path.exists() {
+            warn!("No land cover for tile {:?}", self.coords);
+            return Ok(GrayImage::from_pixel(
+                LABEL_RESOLUTION_PX,
+                LABEL_RESOLUTION_PX,
+                Luma([landcover::LABEL_OTHER]),
+            ));
+        }
+        landcover::rasterize_tile(&path, self.coords.into(), IMAGE_SIZE_M, LABEL_RESOLUTION_PX)
+    }
+
+    /// Textured model with vertex positions relative to the given origin
+    ///
+    /// With labels the model gets a second material at LABEL_MATERIAL with the land cover.
     pub fn model(
         &self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         texture_bind_group_layout: &wgpu::BindGroupLayout,
+        origin: Coords,
+        labels: bool,
     ) -> Result<Model> {
         let resolution = self
             .resolution
@@ -378,14 +506,25 @@ impl GridSquare {
             resolution,
             max_lod
         );
-        Ok(Model {
-            meshes: vec![self.mesh(device)],
-            materials: vec![Material::new(
+        let mut materials = vec![Material::new(
+            device,
+            "dummy mat",
+            diffuse_texture,
+            texture_bind_group_layout,
+        )];
+        if labels {
+            let label_texture =
+                Texture::from_labels(device, queue, &self.labels()?, Some("label texture"));
+            materials.push(Material::new(
                 device,
-                "dummy mat",
-                diffuse_texture,
+                "label mat",
+                label_texture,
                 texture_bind_group_layout,
-            )],
+            ));
+        }
+        Ok(Model {
+            meshes: vec![self.mesh(device, origin)],
+            materials,
         })
     }
 }
@@ -0,0 +1,365 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use image::{GrayImage, Luma};
+use nalgebra::Point2;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Coords;
+
+/// Label of terrain without land cover data, such as the open land that swissTLM3D leaves
+/// unclassified
+pub const LABEL_OTHER: u8 = 0;
+/// Label of pixels where no terrain was rendered
+pub const LABEL_SKY: u8 = 255;
+
+/// Width of rasterized line features such as roads
+const LINE_WIDTH_M: f64 = 6.0;
+
+/// Class of the label image with the swissTLM3D object types (OBJEKTART) it covers
+#[derive(Debug, Serialize)]
+pub struct LabelClass {
+    pub id: u8,
+    pub name: &'static str,
+    pub object_types: &'static [&'static str],
+}
+
+/// All label classes, features of later classes are drawn on top of earlier ones
+pub const LABEL_CLASSES: &[LabelClass] = &[
+    LabelClass {
+        id: LABEL_OTHER,
+        name: "other",
+        object_types: &[],
+    },
+    LabelClass {
+        id: 1,
+        name: "forest",
+        object_types: &["Wald", "Wald offen", "Gebueschwald", "Gehoelzflaeche"],
+    },
+    LabelClass {
+        id: 2,
+        name: "wetland",
+        object_types: &["Sumpf", "Feuchtgebiet"],
+    },
+    LabelClass {
+        id: 3,
+        name: "rock",
+        object_types: &[
+            "Fels",
+            "Fels locker",
+            "Felsbloecke",
+            "Felsbloecke locker",
+            "Lockergestein",
+            "Lockergestein locker",
+        ],
+    },
+    LabelClass {
+        id: 4,
+        name: "glacier",
+        object_types: &["Gletscher", "Schneefeld Toteis"],
+    },
+    LabelClass {
+        id: 5,
+        name: "water",
+        object_types: &["Stehende Gewaesser", "Fliessgewaesser"],
+    },
+    LabelClass {
+        id: 6,
+        name: "road",
+        object_types: &[
+            "Autobahn",
+            "Autostrasse",
+            "Ausfahrt",
+            "Einfahrt",
+            "10m Strasse",
+            "8m Strasse",
+            "6m Strasse",
+            "4m Strasse",
+            "3m Strasse",
+            "2m Weg",
+            "1m Weg",
+            "Platz",
+        ],
+    },
+    LabelClass {
+        id: 7,
+        name: "building",
+        object_types: &["Gebaeude"],
+    },
+    LabelClass {
+        id: LABEL_SKY,
+        name: "sky",
+        object_types: &[],
+    },
+];
+
+/// Position of the class of a feature in LABEL_CLASSES
+///
+/// The class is given by the swissTLM3D OBJEKTART property, or directly by a class property
+/// with the name of the class.
+fn class_index(properties: &Value) -> Option<usize> {
+    let object_type = properties.get("OBJEKTART").and_then(Value::as_str);
+    let class_name = properties.get("class").and_then(Value::as_str);
+    LABEL_CLASSES.iter().position(|class| {
+        class.id != LABEL_SKY
+            && (matches!(object_type, Some(object_type) if class.object_types.contains(&object_type))
+                || class_name == Some(class.name))
+    })
+}
+
+/// Rings or lines of a GeoJSON geometry in LV95
+fn parse_lines(coordinates: &Value) -> Result<Vec<Vec<Point2<f64>>>> {
+    let line = coordinates.as_array().context("Invalid coordinates")?;
+    if matches!(
+        line.first().and_then(|point| point.get(0)),
+        Some(Value::Number(_))
+    ) {
+        let points = line
+            .iter()
+            .map(|point| match (point.get(0), point.get(1)) {
+                (Some(Value::Number(x)), Some(Value::Number(y))) => {
+                    Ok(Point2::new(x.as_f64().unwrap(), y.as_f64().unwrap()))
+                }
+                _ => bail!("Invalid coordinate {}", point),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(point) = points.first() {
+            if point.x < 2_000_000.0 || point.y < 1_000_000.0 {
+                bail!("Land cover coordinates are not in LV95");
+            }
+        }
+        return Ok(vec![points]);
+    }
+    let mut lines = Vec::new();
+    for part in line {
+        lines.append(&mut parse_lines(part)?);
+    }
+    Ok(lines)
+}
+
+/// Raster of one square tile with north up, row 0 at the north edge
+struct TileRaster {
+    image: GrayImage,
+    /// LV95 coordinates of the south west corner
+    corner: Coords,
+    pixel_size_m: f64,
+}
+
+impl TileRaster {
+    /// LV95 coordinates of the center of a pixel
+    fn pixel_center(&self, x: u32, y: u32) -> Point2<f64> {
+        let height = self.image.height();
+        Point2::new(
+            self.corner.x + (x as f64 + 0.5) * self.pixel_size_m,
+            self.corner.y + (height - y) as f64 * self.pixel_size_m - 0.5 * self.pixel_size_m,
+        )
+    }
+
+    /// Fill the pixels inside the rings with the even-odd rule, so holes stay unfilled
+    fn fill_polygon(&mut self, rings: &[Vec<Point2<f64>>], label: u8) {
+        for y in 0..self.image.height() {
+            let northing = self.pixel_center(0, y).y;
+            let mut crossings: Vec<f64> = rings
+                .iter()
+                .flat_map(|ring| ring.iter().zip(ring.iter().cycle().skip(1)))
+                .filter(|(a, b)| (a.y <= northing) != (b.y <= northing))
+                .map(|(a, b)| a.x + (northing - a.y) / (b.y - a.y) * (b.x - a.x))
+                .collect();
+            crossings.sort_by(|a, b| a.total_cmp(b));
+            for span in crossings.chunks_exact(2) {
+                // Pixels whose centers lie between the crossings
+                let start = ((span[0] - self.corner.x) / self.pixel_size_m - 0.5).ceil();
+                let end = ((span[1] - self.corner.x) / self.pixel_size_m - 0.5).floor();
+                let end = end.min(self.image.width() as f64 - 1.0);
+                if end < 0.0 {
+                    continue;
+                }
+                for x in start.max(0.0) as u32..=end as u32 {
+                    self.image.put_pixel(x, y, Luma([label]));
+                }
+            }
+        }
+    }
+
+    /// Fill the pixels within half the line width of the line
+    fn draw_line(&mut self, line: &[Point2<f64>], label: u8) {
+        let half_width_m = 0.5 * LINE_WIDTH_M;
+        let (width, height) = self.image.dimensions();
+        for segment in line.windows(2) {
+            let (a, b) = (segment[0], segment[1]);
+            let to_px = |easting: f64| (easting - self.corner.x) / self.pixel_size_m;
+            let to_row =
+                |northing: f64| height as f64 - (northing - self.corner.y) / self.pixel_size_m;
+            let x_range = (
+                to_px(a.x.min(b.x) - half_width_m).floor().max(0.0) as u32,
+                (to_px(a.x.max(b.x) + half_width_m).ceil().max(0.0) as u32).min(width),
+            );
+            let y_range = (
+                to_row(a.y.max(b.y) + half_width_m).floor().max(0.0) as u32,
+                (to_row(a.y.min(b.y) - half_width_m).ceil().max(0.0) as u32).min(height),
+            );
+            let direction = b - a;
+            let length2 = direction.norm_squared().max(f64::EPSILON);
+            for y in y_range.0..y_range.1 {
+                for x in x_range.0..x_range.1 {
+                    let center = self.pixel_center(x, y);
+                    let t = ((center - a).dot(&direction) / length2).clamp(0.0, 1.0);
+                    if (center - (a + t * direction)).norm() <= half_width_m {
+                        self.image.put_pixel(x, y, Luma([label]));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rasterize the land cover features of a square tile into an image of class ids
+///
+/// The features are read from a GeoJSON FeatureCollection in LV95, e.g. an export of the
+/// swissTLM3D land cover, building footprint and road layers. Shapefiles can be converted with
+/// `ogr2ogr -f GeoJSON`. Polygons are filled and lines are drawn with a fixed width, pixels
+/// without a feature are LABEL_OTHER.
+///
+/// # Arguments
+///
+/// * `path` - GeoJSON file with the features of the tile
+/// * `corner` - LV95 coordinates of the south west corner of the tile
+/// * `size_m` - Side length of the tile
+/// * `resolution_px` - Side length of the image
+pub fn rasterize_tile(
+    path: &Path,
+    corner: Coords,
+    size_m: f32,
+    resolution_px: u32,
+) -> Result<GrayImage> {
+    let geojson: Value =
+        serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    let features = geojson
+        .get("features")
+        .and_then(Value::as_array)
+        .context("Land cover is not a GeoJSON FeatureCollection")?;
+    let mut classified = Vec::new();
+    for feature in features {
+        if let (Some(properties), Some(geometry)) =
+            (feature.get("properties"), feature.get("geometry"))
+        {
+            if let Some(index) = class_index(properties) {
+                classified.push((index, geometry));
+            }
+        }
+    }
+    // Draw the classes in the order of their priority
+    classified.sort_by_key(|(index, _)| *index);
+
+    let mut raster = TileRaster {
+        image: GrayImage::from_pixel(resolution_px, resolution_px, Luma([LABEL_OTHER])),
+        corner,
+        pixel_size_m: size_m as f64 / resolution_px as f64,
+    };
+    for (index, geometry) in classified {
+        let label = LABEL_CLASSES[index].id;
+        let coordinates = geometry.get("coordinates").unwrap_or(&Value::Null);
+        match geometry.get("type").and_then(Value::as_str) {
+            // The parts of a multi polygon do not overlap, so they can be filled together
+            Some("Polygon") | Some("MultiPolygon") => {
+                raster.fill_polygon(&parse_lines(coordinates)?, label)
+            }
+            Some("LineString") | Some("MultiLineString") => {
+                for line in parse_lines(coordinates)? {
+                    raster.draw_line(&line, label);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(raster.image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Forest with a hole, a building in one half of the hole and a road along the northern
+    /// edge of a 100 m tile at 2600000, 1200000
+    const GEOJSON: &str = r#"{
+        "type": "FeatureCollection",
+        "features": [
+            {
+                "type": "Feature",
+                "properties": { "OBJEKTART": "Gebaeude" },
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[
+                        [2600041, 1200041], [2600049, 1200041],
+                        [2600049, 1200059], [2600041, 1200059], [2600041, 1200041]
+                    ]]
+                }
+            },
+            {
+                "type": "Feature",
+                "properties": { "OBJEKTART": "Wald" },
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [
+                        [
+                            [2600010, 1200010], [2600090, 1200010],
+                            [2600090, 1200090], [2600010, 1200090], [2600010, 1200010]
+                        ],
+                        [
+                            [2600040, 1200040], [2600040, 1200060],
+                            [2600060, 1200060], [2600060, 1200040], [2600040, 1200040]
+                        ]
+                    ]
+                }
+            },
+            {
+                "type": "Feature",
+                "properties": { "class": "road" },
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": [[2600000, 1200095], [2600100, 1200095]]
+                }
+            },
+            {
+                "type": "Feature",
+                "properties": { "OBJEKTART": "Unbekannt" },
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[
+                        [2600000, 1200000], [2600100, 1200000],
+                        [2600100, 1200100], [2600000, 1200100], [2600000, 1200000]
+                    ]]
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn polygon_with_hole() {
+        let path = std::env::temp_dir().join(format!("landcover-{}.geojson", std::process::id()));
+        std::fs::write(&path, GEOJSON).unwrap();
+        let image = rasterize_tile(&path, Coords::new(2_600_000.0, 1_200_000.0, 0.0), 100.0, 10);
+        std::fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+
+        // Pixels are 10 m with row 0 at the northern edge
+        let label = |x, y| image.get_pixel(x, y)[0];
+        for x in 0..10 {
+            assert_eq!(label(x, 0), 6, "road at {}", x);
+            assert_eq!(label(x, 9), LABEL_OTHER);
+        }
+        for y in 1..9 {
+            assert_eq!(label(0, y), LABEL_OTHER);
+            assert_eq!(label(9, y), LABEL_OTHER);
+            for x in 1..9 {
+                let expected = match (x, y) {
+                    (4, 4..=5) => 7,
+                    (5, 4..=5) => LABEL_OTHER,
+                    _ => 1,
+                };
+                assert_eq!(label(x, y), expected, "label at {}, {}", x, y);
+            }
+        }
+    }
+}
@@ -1,11 +1,24 @@
 use nalgebra::Point3;
 
+pub mod calibration;
 pub mod camera;
 pub mod config;
+pub mod cubemap;
+pub mod depth;
+pub mod geodesy;
+pub mod geoid;
 pub mod gridsquare;
+pub mod landcover;
 pub mod model;
+pub mod normalmap;
+pub mod orthophoto;
+pub mod pose;
 pub mod renderer;
+pub mod rig;
+pub mod rolling_shutter;
 pub mod terraingrid;
 pub mod texture;
+pub mod worldmap;
 
-pub type Coords = Point3<f32>;
+/// World position in LV95, in f64 to keep centimeters at coordinates of millions of meters
+pub type Coords = Point3<f64>;
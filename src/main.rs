@@ -1,42 +1,71 @@
 use std::convert::TryInto;
 use std::fs::create_dir_all;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
-use image::DynamicImage;
+use itertools::Itertools;
 use log::{debug, info};
 use nalgebra::Point3;
 use rayon::iter::ParallelIterator;
 use rayon::prelude::IntoParallelIterator;
 use serde::Serialize;
 
-use geo_renderer::camera::Intrinsics;
-use geo_renderer::config::StorageConfig;
+use geo_renderer::calibration::CalibrationConfig;
+use geo_renderer::camera::{Camera, Intrinsics};
+use geo_renderer::config::{ElevationSource, StorageConfig};
+use geo_renderer::depth::{DepthEncoding, DepthMapping, DepthMode, DepthSemantics};
+use geo_renderer::geodesy::Wgs84;
+use geo_renderer::geoid::{self, Geoid};
 use geo_renderer::gridsquare::GridCoords;
+use geo_renderer::landcover::{LabelClass, LABEL_CLASSES};
+use geo_renderer::normalmap::NormalMapPaths;
 use geo_renderer::renderer::{RenderRequest, Renderer, RequestPose};
 use geo_renderer::Coords;
 
 #[derive(Parser)]
 struct Flags {
-    /// Leftmost chunk to render in LV95 in km
+    /// Chunks to render
+    #[clap(flatten)]
+    bounds: ChunkBounds,
+    /// Minimum view distance to render in m, at most 100km
     #[clap(long)]
-    min_easting: i32,
-    /// Rightmost chunk to render in LV95 in km
+    view_range_m: f32,
+    /// Also render a level 360 degree panorama of this width at each pose, multiple of 64
     #[clap(long)]
-    max_easting: i32,
-    /// Bottom chunk to render in LV95 in km
+    panorama_width_px: Option<u32>,
+    /// Bend the terrain for earth curvature with this refraction coefficient, 0.13 for a standard
+    /// atmosphere
     #[clap(long)]
-    min_northing: i32,
-    /// Top chunk to render in LV95 in km
+    refraction_coefficient: Option<f32>,
+    /// How distances are stored in the depth buffer, the far plane of all modes is beyond the
+    /// view range and reverse_z and logarithmic stay precise at long ranges
+    #[clap(long, value_enum, default_value_t)]
+    depth_mode: DepthMode,
+    /// Whether the depth images hold the range along the ray or the z-depth along the optical
+    /// axis
+    #[clap(long, value_enum, default_value_t)]
+    depth_semantics: DepthSemantics,
+    /// Also store the LV95 position of the surface seen by each pixel
     #[clap(long)]
-    max_northing: i32,
-    /// Minimum view distance to render in m, at most 100km
+    world_coordinates: bool,
+    /// Also store the world and camera space normal, slope and aspect of the surface seen by
+    /// each pixel
     #[clap(long)]
-    view_range_m: f32,
+    normals: bool,
+    /// Also store the land cover class of the surface seen by each pixel, needs --landcover-dir
+    #[clap(long)]
+    labels: bool,
+    /// Also store the height of the surface seen by each pixel above the bare terrain (nDSM),
+    /// from the difference of the surface and altitude models
+    #[clap(long)]
+    object_height: bool,
     /// Folder where the data will be saved
     #[clap(long)]
     output_dir: PathBuf,
+    /// Camera calibration to render with
+    #[clap(flatten)]
+    calibration: CalibrationConfig,
     /// Paths to the swisstopo data
     #[clap(flatten)]
     storage_config: StorageConfig,
@@ -47,16 +76,122 @@ struct Flags {
 
 impl Flags {
     pub fn validate(&mut self) -> Result<()> {
-        (self.min_northing, self.max_northing) = (
-            self.min_northing.min(self.max_northing),
-            self.min_northing.max(self.max_northing),
+        self.storage_config.validate()?;
+        if self.object_height {
+            self.storage_config.validate_object_height()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct ChunkBounds {
+    /// Leftmost chunk to render in LV95 in km
+    #[clap(long)]
+    min_easting: Option<i32>,
+    /// Rightmost chunk to render in LV95 in km
+    #[clap(long)]
+    max_easting: Option<i32>,
+    /// Bottom chunk to render in LV95 in km
+    #[clap(long)]
+    min_northing: Option<i32>,
+    /// Top chunk to render in LV95 in km
+    #[clap(long)]
+    max_northing: Option<i32>,
+    /// Southern bound of the area to render in WGS84 degrees, instead of the LV95 chunks
+    #[clap(long)]
+    min_latitude_deg: Option<f64>,
+    /// Northern bound of the area to render in WGS84 degrees
+    #[clap(long)]
+    max_latitude_deg: Option<f64>,
+    /// Western bound of the area to render in WGS84 degrees
+    #[clap(long)]
+    min_longitude_deg: Option<f64>,
+    /// Eastern bound of the area to render in WGS84 degrees
+    #[clap(long)]
+    max_longitude_deg: Option<f64>,
+}
+
+/// Points per edge of a WGS84 bounding box sampled for its LV95 extent
+const WGS84_EDGE_SAMPLES: usize = 16;
+
+impl ChunkBounds {
+    /// Bottom left and top right chunk, inclusive
+    fn chunks(&self) -> Result<(GridCoords, GridCoords)> {
+        let lv95 = (
+            self.min_easting,
+            self.max_easting,
+            self.min_northing,
+            self.max_northing,
         );
-        (self.min_easting, self.max_easting) = (
-            self.min_easting.min(self.max_easting),
-            self.min_easting.max(self.max_easting),
+        let wgs84 = (
+            self.min_latitude_deg,
+            self.max_latitude_deg,
+            self.min_longitude_deg,
+            self.max_longitude_deg,
         );
-
-        self.storage_config.validate()
+        match (lv95, wgs84) {
+            (
+                (Some(min_easting), Some(max_easting), Some(min_northing), Some(max_northing)),
+                (None, None, None, None),
+            ) => Ok((
+                GridCoords::new(min_easting.min(max_easting), min_northing.min(max_northing)),
+                GridCoords::new(min_easting.max(max_easting), min_northing.max(max_northing)),
+            )),
+            (
+                (None, None, None, None),
+                (
+                    Some(min_latitude_deg),
+                    Some(max_latitude_deg),
+                    Some(min_longitude_deg),
+                    Some(max_longitude_deg),
+                ),
+            ) => {
+                // Parallels and meridians are curved in LV95, so the edges of the box are sampled
+                // for the chunks covering it
+                let chunks: Vec<GridCoords> = (0..=WGS84_EDGE_SAMPLES)
+                    .map(|step| step as f64 / WGS84_EDGE_SAMPLES as f64)
+                    .flat_map(|fraction| {
+                        let latitude_deg =
+                            min_latitude_deg + fraction * (max_latitude_deg - min_latitude_deg);
+                        let longitude_deg =
+                            min_longitude_deg + fraction * (max_longitude_deg - min_longitude_deg);
+                        [
+                            (latitude_deg, min_longitude_deg),
+                            (latitude_deg, max_longitude_deg),
+                            (min_latitude_deg, longitude_deg),
+                            (max_latitude_deg, longitude_deg),
+                        ]
+                    })
+                    .map(|(latitude_deg, longitude_deg)| {
+                        Wgs84 {
+                            latitude_deg,
+                            longitude_deg,
+                            height_m: 0.0,
+                        }
+                        .to_lv95()
+                        .into()
+                    })
+                    .collect();
+                let (min_x, max_x) = chunks
+                    .iter()
+                    .map(|chunk| chunk.0.x)
+                    .minmax()
+                    .into_option()
+                    .unwrap();
+                let (min_y, max_y) = chunks
+                    .iter()
+                    .map(|chunk| chunk.0.y)
+                    .minmax()
+                    .into_option()
+                    .unwrap();
+                Ok((GridCoords::new(min_x, min_y), GridCoords::new(max_x, max_y)))
+            }
+            _ => bail!(
+                "Either all LV95 chunk bounds or all WGS84 latitude and longitude bounds have to \
+                 be given"
+            ),
+        }
     }
 }
 
@@ -64,13 +199,13 @@ impl Flags {
 struct LV95Coords {
     /// North coordinate to render in LV95
     #[clap(long)]
-    easting_m: f32,
+    easting_m: f64,
     /// East coordinate to render in LV95
     #[clap(long)]
-    northing_m: f32,
+    northing_m: f64,
     /// Altitude above ground level to render, in meters
     #[clap(long)]
-    altitude_m: f32,
+    altitude_m: f64,
 }
 
 impl From<LV95Coords> for Coords {
@@ -93,7 +228,29 @@ impl From<Coords> for LV95Coords {
 struct Image {
     rgb_image_path: PathBuf,
     depth_image_path: PathBuf,
+    /// LV95 position of the surface seen by each pixel minus world_origin_lv95, float32 of shape
+    /// (height, width, 3), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    world_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    world_origin_lv95: Option<LV95Coords>,
+    /// Float32 normals of shape (height, width, 3) and slope and aspect in degrees of shape
+    /// (height, width), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normal_images: Option<NormalMapPaths>,
+    /// Class id of each pixel, see label_classes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_image_path: Option<PathBuf>,
+    /// Float32 height above the bare terrain of shape (height, width), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    object_height_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_rgb_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_depth_image_path: Option<PathBuf>,
     camera_pos_lv95: LV95Coords,
+    camera_pos_wgs84: Wgs84,
+    depth_mapping: DepthMapping,
     camera_forward: [f32; 3],
     camera_up: [f32; 3],
 }
@@ -102,36 +259,75 @@ struct Image {
 struct RenderedDataset {
     images: Vec<Image>,
     intrinsics: Intrinsics,
+    /// Valid-pixel mask shared by all images
+    mask_image_path: PathBuf,
+    /// Unit ray per pixel in the camera frame, float32 of shape (height, width, 3)
+    bearing_table_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_intrinsics: Option<Intrinsics>,
+    /// Refraction coefficient if the terrain was bent for earth curvature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refraction_coefficient: Option<f32>,
+    depth_encoding: DepthEncoding,
+    /// Elevation model the terrain was built from
+    elevation_source: ElevationSource,
+    /// Classes of the label images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_classes: Option<&'static [LabelClass]>,
 }
 
-async fn render_chunk(
-    chunk_coords: GridCoords,
-    view_range_m: f32,
-    storage_config: &StorageConfig,
-    output_dir: &Path,
-) -> Result<()> {
-    let intrinsics = Intrinsics::load("camera_params.toml")?;
-    let output_dir = output_dir.join(format!("render_{}_{}", chunk_coords.0.x, chunk_coords.0.y));
+async fn render_chunk(chunk_coords: GridCoords, args: &Flags) -> Result<()> {
+    let intrinsics = args.calibration.load()?;
+    let output_dir = args
+        .output_dir
+        .join(format!("render_{}_{}", chunk_coords.0.x, chunk_coords.0.y));
     create_dir_all(&output_dir)?;
     let image_json_path = output_dir.join("images.json");
     if image_json_path.exists() {
         info!("Found existing images.json, skipping chunk");
         return Ok(());
     }
+    let geoid = args
+        .storage_config
+        .geoid_path
+        .as_ref()
+        .map(Geoid::load)
+        .transpose()?;
     let mut state = Renderer::new(intrinsics.clone()).await;
+    let panorama_intrinsics = args.panorama_width_px.map(Intrinsics::equirectangular);
+    if let Some(panorama_intrinsics) = &panorama_intrinsics {
+        state.enable_panorama(panorama_intrinsics.clone())?;
+    }
+    if let Some(refraction_coefficient) = args.refraction_coefficient {
+        state.enable_earth_curvature(refraction_coefficient);
+    }
+    state.set_depth_mode(args.depth_mode);
+    state.set_depth_semantics(args.depth_semantics);
+    if args.world_coordinates {
+        state.enable_world_coordinates();
+    }
+    if args.normals {
+        state.enable_normals();
+    }
+    if args.labels {
+        state.enable_labels();
+    }
+    if args.object_height {
+        state.enable_object_height();
+    }
 
     let camera_pos: Coords = chunk_coords.into();
     let mut camera_positions: Vec<Coords> = Vec::new();
     for agl_m in [300, 550, 800, 1200, 2000, 2800] {
         let resolution = 1500 / agl_m + 1;
-        let step_m = 1000.0 / resolution as f32;
+        let step_m = 1000.0 / resolution as f64;
         let offset_m = step_m / 2.0;
         for x_step in 0..resolution {
             for y_step in 0..resolution {
-                camera_positions.push(Point3::<f32>::new(
-                    camera_pos.x + step_m * x_step as f32 + offset_m,
-                    camera_pos.y + step_m * y_step as f32 + offset_m,
-                    agl_m as f32,
+                camera_positions.push(Point3::<f64>::new(
+                    camera_pos.x + step_m * x_step as f64 + offset_m,
+                    camera_pos.y + step_m * y_step as f64 + offset_m,
+                    agl_m as f64,
                 ));
             }
         }
@@ -143,53 +339,82 @@ async fn render_chunk(
             camera_pose: RequestPose::PositionAgl {
                 camera_pos_agl: pos,
             },
+            rolling_shutter: None,
+            rig: None,
+            mount: None,
+            intrinsics: None,
             request_id: id as u32,
         })
         .collect();
     let rendered_requests = state
-        .render_images(render_requests, view_range_m, storage_config)
+        .render_images(render_requests, args.view_range_m, &args.storage_config)
         .await?;
 
     info!("Storing {} images", rendered_requests.len());
     let images = rendered_requests
         .into_par_iter()
-        .map(|request| {
+        .map(|request| -> Result<Image> {
             let filename = output_dir.join(format!("image_{}", request.request_id));
             let rgb_image_path = filename.with_extension("png");
             let depth_image_path = filename.with_extension("bin");
 
-            let image_rgba = DynamicImage::ImageRgba8(request.image_rgba);
-            image_rgba.save(&rgb_image_path).unwrap();
+            request.image_rgba.save(&rgb_image_path)?;
 
             let depth_bin: &[u8] = bytemuck::cast_slice(&request.image_depth);
-            std::fs::write(&depth_image_path, depth_bin).unwrap();
+            std::fs::write(&depth_image_path, depth_bin)?;
+
+            let outputs = request.save_outputs(&filename)?;
 
-            Image {
+            Ok(Image {
                 rgb_image_path: PathBuf::from(rgb_image_path.file_name().expect("")),
                 depth_image_path: PathBuf::from(depth_image_path.file_name().expect("")),
+                world_image_path: outputs.world_image_path,
+                world_origin_lv95: request
+                    .image_world
+                    .as_ref()
+                    .map(|world| world.origin.into()),
+                normal_images: outputs.normal_images,
+                label_image_path: outputs.label_image_path,
+                object_height_image_path: outputs.object_height_image_path,
+                panorama_rgb_image_path: outputs.panorama_rgb_image_path,
+                panorama_depth_image_path: outputs.panorama_depth_image_path,
                 camera_pos_lv95: request.camera_pos_lv95.into(),
+                camera_pos_wgs84: geoid::lv95_to_wgs84(request.camera_pos_lv95, geoid.as_ref())?,
+                depth_mapping: request.depth_mapping,
                 camera_forward: request.camera_forward.as_slice().try_into().unwrap(),
                 camera_up: request.camera_up.as_slice().try_into().unwrap(),
-            }
+            })
         })
-        .collect();
-    let dataset = RenderedDataset { images, intrinsics };
+        .collect::<Result<_>>()?;
+    let camera = Camera::new(Coords::origin(), intrinsics.clone());
+    let mask_image_path = output_dir.join("mask.png");
+    camera.valid_mask().save(&mask_image_path)?;
+    let bearing_table_path = output_dir.join("bearings.bin");
+    let bearing_table = camera.bearing_table();
+    let bearing_bin: &[u8] = bytemuck::cast_slice(bearing_table.as_slice().unwrap());
+    std::fs::write(&bearing_table_path, bearing_bin)?;
+    let dataset = RenderedDataset {
+        images,
+        intrinsics,
+        mask_image_path: PathBuf::from(mask_image_path.file_name().expect("")),
+        bearing_table_path: PathBuf::from(bearing_table_path.file_name().expect("")),
+        panorama_intrinsics,
+        refraction_coefficient: args.refraction_coefficient,
+        depth_encoding: DepthEncoding::new(args.depth_semantics),
+        elevation_source: args.storage_config.elevation_source,
+        label_classes: args.labels.then_some(LABEL_CLASSES),
+    };
     std::fs::write(image_json_path, serde_json::to_string_pretty(&dataset)?)?;
     Ok(())
 }
 
 async fn run(mut args: Flags) -> Result<()> {
     args.validate()?;
-    for x in args.min_easting..=args.max_easting {
-        for y in args.min_northing..=args.max_northing {
+    let (min_chunk, max_chunk) = args.bounds.chunks()?;
+    for x in min_chunk.0.x..=max_chunk.0.x {
+        for y in min_chunk.0.y..=max_chunk.0.y {
             let chunk_coords = GridCoords::new(x, y);
-            render_chunk(
-                chunk_coords,
-                args.view_range_m,
-                &args.storage_config,
-                &args.output_dir,
-            )
-            .await?;
+            render_chunk(chunk_coords, &args).await?;
         }
     }
     Ok(())
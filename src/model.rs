@@ -11,6 +11,10 @@ pub trait Vertex {
 pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
+    /// Unit normal of the terrain in LV95 axes
+    pub normal: [f32; 3],
+    /// Height of the surface above the bare terrain, 0 if not loaded
+    pub object_height_m: f32,
 }
 
 impl Vertex for ModelVertex {
@@ -32,6 +36,18 @@ impl Vertex for ModelVertex {
                     shader_location: 1,
                     format: wgpu::VertexFormat::Float32x2,
                 },
+                wgpu::VertexAttribute {
+                    // Normal
+                    offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    // Object height
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32,
+                },
             ],
         }
     }
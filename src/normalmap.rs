@@ -0,0 +1,87 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use nalgebra::{Matrix3, Vector3};
+use serde::Serialize;
+
+/// Orientation of the terrain surface seen by each pixel of an image
+///
+/// All channels are in row major order and NaN where no terrain was rendered. The normals are
+/// the ones of the terrain model, they are not bent for earth curvature.
+#[derive(Debug, Clone, Default)]
+pub struct NormalMap {
+    /// Unit normal in LV95 axes (east, north, up), of shape (height, width, 3)
+    pub world: Vec<f32>,
+    /// Unit normal in the camera frame (x right, y down, z forward), of shape (height, width, 3)
+    pub camera: Vec<f32>,
+    /// Angle between the normal and the vertical in degrees
+    pub slope_deg: Vec<f32>,
+    /// Azimuth of the downhill direction in degrees clockwise from north, NaN on flat terrain
+    pub aspect_deg: Vec<f32>,
+}
+
+/// File names of the channels of a saved NormalMap, each a raw float32 array
+#[derive(Debug, Clone, Serialize)]
+pub struct NormalMapPaths {
+    pub world_normal_path: PathBuf,
+    pub camera_normal_path: PathBuf,
+    pub slope_path: PathBuf,
+    pub aspect_path: PathBuf,
+}
+
+impl NormalMap {
+    /// Derive all channels from world normals rendered with one camera rotation per band of rows
+    ///
+    /// # Arguments
+    ///
+    /// * `world` - Interpolated normal of each pixel in LV95 axes, not necessarily normalized
+    /// * `band_rotations` - Camera rotation (see Camera::rotation) and rows of each band
+    /// * `width` - Width of the image in pixels
+    pub fn new(world: Vec<f32>, band_rotations: &[(Matrix3<f32>, Range<u32>)], width: u32) -> Self {
+        let pixels = world.len() / 3;
+        let mut normal_map = NormalMap {
+            world: Vec::with_capacity(3 * pixels),
+            camera: Vec::with_capacity(3 * pixels),
+            slope_deg: Vec::with_capacity(pixels),
+            aspect_deg: Vec::with_capacity(pixels),
+        };
+        for (rotation, rows) in band_rotations {
+            let inverse_rotation = rotation.transpose();
+            let start = (rows.start * width) as usize;
+            let end = ((rows.end * width) as usize).min(pixels);
+            for normal in world[3 * start..3 * end].chunks_exact(3) {
+                let normal = Vector3::new(normal[0], normal[1], normal[2]).normalize();
+                let camera_normal = inverse_rotation * normal;
+                normal_map.world.extend(normal.iter());
+                normal_map.camera.extend(camera_normal.iter());
+                let slope_deg = normal.z.clamp(-1.0, 1.0).acos().to_degrees();
+                let aspect_deg = if normal.x == 0.0 && normal.y == 0.0 {
+                    f32::NAN
+                } else {
+                    normal.x.atan2(normal.y).to_degrees().rem_euclid(360.0)
+                };
+                normal_map.slope_deg.push(slope_deg);
+                normal_map.aspect_deg.push(aspect_deg);
+            }
+        }
+        normal_map
+    }
+
+    /// Write each channel next to the given path, adding a suffix to its file name
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<NormalMapPaths> {
+        let path = path.as_ref();
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let write = |suffix: &str, data: &[f32]| -> Result<PathBuf> {
+            let file_name = PathBuf::from(format!("{}_{}.bin", stem, suffix));
+            std::fs::write(path.with_file_name(&file_name), bytemuck::cast_slice(data))?;
+            Ok(file_name)
+        };
+        Ok(NormalMapPaths {
+            world_normal_path: write("normal_world", &self.world)?,
+            camera_normal_path: write("normal_camera", &self.camera)?,
+            slope_path: write("slope", &self.slope_deg)?,
+            aspect_path: write("aspect", &self.aspect_deg)?,
+        })
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use image::{ImageBuffer, Rgba};
+use tiff::encoder::{colortype, DirectoryEncoder, TiffEncoder, TiffKind};
+use tiff::tags::Tag;
+
+use crate::Coords;
+
+/// EPSG code of the LV95 (CH1903+) projected coordinate system
+const EPSG_LV95: u16 = 2056;
+
+/// GeoTIFF key directory declaring a projected LV95 raster with pixels covering an area
+const GEO_KEY_DIRECTORY: [u16; 16] = [
+    1, 1, 0, 3, // Version 1.1.0 with 3 keys
+    1024, 0, 1, 1, // GTModelTypeGeoKey: projected
+    1025, 0, 1, 1, // GTRasterTypeGeoKey: pixel is area
+    3072, 0, 1, EPSG_LV95, // ProjectedCSTypeGeoKey
+];
+
+/// North up nadir rendering of an LV95 bounding box with square pixels
+#[derive(Debug, Default)]
+pub struct Orthophoto {
+    /// LV95 coordinates of the top left (north west) corner of the top left pixel
+    pub origin: Coords,
+    /// Ground sampling distance, the side length of a pixel in meters
+    pub gsd_m: f32,
+    pub image_rgba: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    /// Absolute elevation of the visible surface in meters, NaN where no terrain was rendered
+    pub elevation_m: Vec<f32>,
+}
+
+impl Orthophoto {
+    /// Write the color image as a georeferenced RGB GeoTIFF
+    pub fn save_rgb<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let rgb: Vec<u8> = self
+            .image_rgba
+            .pixels()
+            .flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+            .collect();
+        let mut encoder = TiffEncoder::new(File::create(path)?)?;
+        let mut image = encoder
+            .new_image::<colortype::RGB8>(self.image_rgba.width(), self.image_rgba.height())?;
+        self.write_georeference(image.encoder())?;
+        image.write_data(&rgb)?;
+        Ok(())
+    }
+
+    /// Write the elevation as a georeferenced single band float32 GeoTIFF (DSM)
+    pub fn save_elevation<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut encoder = TiffEncoder::new(File::create(path)?)?;
+        let mut image = encoder.new_image::<colortype::Gray32Float>(
+            self.image_rgba.width(),
+            self.image_rgba.height(),
+        )?;
+        self.write_georeference(image.encoder())?;
+        image.encoder().write_tag(Tag::GdalNodata, "nan")?;
+        image.write_data(&self.elevation_m)?;
+        Ok(())
+    }
+
+    /// Pixel scale, tie point and LV95 key directory tags
+    fn write_georeference<W: Write + Seek, K: TiffKind>(
+        &self,
+        encoder: &mut DirectoryEncoder<W, K>,
+    ) -> Result<()> {
+        let gsd_m = self.gsd_m as f64;
+        encoder.write_tag(Tag::ModelPixelScaleTag, &[gsd_m, gsd_m, 0.0][..])?;
+        // Raster (0, 0) is tied to the top left corner in LV95
+        let (easting_m, northing_m) = (self.origin.x, self.origin.y);
+        encoder.write_tag(
+            Tag::ModelTiepointTag,
+            &[0.0, 0.0, 0.0, easting_m, northing_m, 0.0][..],
+        )?;
+        encoder.write_tag(Tag::GeoKeyDirectoryTag, &GEO_KEY_DIRECTORY[..])?;
+        Ok(())
+    }
+}
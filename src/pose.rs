@@ -0,0 +1,279 @@
+use std::path::Path;
+
+use anyhow::Result;
+use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::Coords;
+
+/// Forward and up vector in LV95 of a camera looking straight down with north at the image bottom
+pub const NADIR: ([f32; 3], [f32; 3]) = ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]);
+
+/// Maps vectors from north-east-down to LV95 (east-north-up)
+fn ned_to_lv95() -> Matrix3<f32> {
+    Matrix3::new(0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, -1.0)
+}
+
+/// Forward and up vector of a camera attitude given as aviation Tait-Bryan angles
+///
+/// The body frame is x forward, y right, z down and is rotated from north-east-down by yaw about
+/// z, then pitch about the new y and finally roll about the new x (intrinsic z-y'-x''). Yaw is
+/// the heading clockwise from north, pitch is positive nose up and roll positive right wing
+/// down. The camera looks along the body x axis with the image y axis along body z, so all
+/// angles zero gives a level view to the north.
+pub fn yaw_pitch_roll_ned(
+    yaw_rad: f32,
+    pitch_rad: f32,
+    roll_rad: f32,
+) -> (Vector3<f32>, Vector3<f32>) {
+    let body_to_ned = Rotation3::from_euler_angles(roll_rad, pitch_rad, yaw_rad);
+    let body_to_lv95 = ned_to_lv95() * body_to_ned.matrix();
+    (body_to_lv95 * Vector3::x(), -(body_to_lv95 * Vector3::z()))
+}
+
+/// Forward and up vector of a rotation from the camera frame (x right, y down, z forward) to LV95
+pub fn quaternion(camera_to_lv95: UnitQuaternion<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    (
+        camera_to_lv95 * Vector3::z(),
+        -(camera_to_lv95 * Vector3::y()),
+    )
+}
+
+/// Forward and up vector of photogrammetric omega, phi, kappa angles
+///
+/// The rotation from the image frame (x right, y up, looking along -z) to LV95 is
+/// R = R_x(omega) R_y(phi) R_z(kappa), i.e. the rotations are applied about the fixed LV95 axes
+/// in the order kappa, phi, omega. All angles zero gives a nadir view with north at the image
+/// top, the transpose of R is the object to image rotation of the collinearity equations.
+pub fn omega_phi_kappa(
+    omega_rad: f32,
+    phi_rad: f32,
+    kappa_rad: f32,
+) -> (Vector3<f32>, Vector3<f32>) {
+    let image_to_lv95 = Rotation3::from_axis_angle(&Vector3::x_axis(), omega_rad)
+        * Rotation3::from_axis_angle(&Vector3::y_axis(), phi_rad)
+        * Rotation3::from_axis_angle(&Vector3::z_axis(), kappa_rad);
+    (image_to_lv95 * -Vector3::z(), image_to_lv95 * Vector3::y())
+}
+
+/// Forward and up vector of a camera at `position` looking at `target`
+///
+/// The image is level, i.e. the image y axis lies in the vertical plane. Views straight up or
+/// down have north at the image top.
+pub fn look_at(position: Coords, target: Coords) -> (Vector3<f32>, Vector3<f32>) {
+    let forward = (target - position).cast::<f32>().normalize();
+    let up = if forward.xy().norm() < 1e-3 {
+        Vector3::y()
+    } else {
+        Vector3::z()
+    };
+    (forward, up)
+}
+
+/// Position and orientation in LV95
+#[derive(Debug, Copy, Clone)]
+pub struct Pose {
+    pub position: Coords,
+    pub forward: Vector3<f32>,
+    pub up: Vector3<f32>,
+}
+
+/// Mounting of a camera on a vehicle whose pose is given instead of the camera pose
+///
+/// The body frame is x forward along the forward vector of the pose, y right and z down. The
+/// camera looks along x of the mount frame, which is rotated from the body frame by the fixed
+/// boresight angles and then by the gimbal yaw about z and pitch about the new y (positive up),
+/// so a gimbal pitch of -90 degrees looks straight down.
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Default)]
+#[serde(default)]
+pub struct Mount {
+    /// Position of the camera in the body frame in meters
+    pub lever_arm_m: [f32; 3],
+    /// Fixed roll, pitch and yaw of the mount relative to the body, applied as z-y'-x''
+    pub boresight_rpy_rad: [f32; 3],
+    pub gimbal_yaw_rad: f32,
+    pub gimbal_pitch_rad: f32,
+}
+
+impl Mount {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Pose of the camera mounted on a body with the given pose
+    pub fn camera_pose(&self, body: &Pose) -> Pose {
+        let forward = body.forward.normalize();
+        let right = forward.cross(&body.up).normalize();
+        let down = forward.cross(&right);
+        let body_to_lv95 = Matrix3::from_columns(&[forward, right, down]);
+        let [roll, pitch, yaw] = self.boresight_rpy_rad;
+        let mount_to_body = Rotation3::from_euler_angles(roll, pitch, yaw)
+            * Rotation3::from_euler_angles(0.0, self.gimbal_pitch_rad, self.gimbal_yaw_rad);
+        let mount_to_lv95 = body_to_lv95 * mount_to_body.matrix();
+        Pose {
+            position: body.position + (body_to_lv95 * Vector3::from(self.lever_arm_m)).cast(),
+            forward: mount_to_lv95 * Vector3::x(),
+            up: -(mount_to_lv95 * Vector3::z()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn assert_orientation(
+        (forward, up): (Vector3<f32>, Vector3<f32>),
+        expected_forward: [f32; 3],
+        expected_up: [f32; 3],
+    ) {
+        assert!(
+            (forward - Vector3::from(expected_forward)).norm() < 1e-6,
+            "forward {}",
+            forward
+        );
+        assert!((up - Vector3::from(expected_up)).norm() < 1e-6, "up {}", up);
+    }
+
+    #[test]
+    fn yaw_pitch_roll_conventions() {
+        // Level view to the north, yaw clockwise to the east
+        assert_orientation(
+            yaw_pitch_roll_ned(0.0, 0.0, 0.0),
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        );
+        assert_orientation(
+            yaw_pitch_roll_ned(FRAC_PI_2, 0.0, 0.0),
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+        );
+        // Nose up looks up, right wing down turns the image top to the east
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        assert_orientation(
+            yaw_pitch_roll_ned(0.0, 30f32.to_radians(), 0.0),
+            [0.0, cos, sin],
+            [0.0, -sin, cos],
+        );
+        assert_orientation(
+            yaw_pitch_roll_ned(0.0, 0.0, FRAC_PI_2),
+            [0.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+        );
+        // The pitch is about the axis after the yaw
+        assert_orientation(
+            yaw_pitch_roll_ned(FRAC_PI_2, 30f32.to_radians(), 0.0),
+            [cos, 0.0, sin],
+            [-sin, 0.0, cos],
+        );
+    }
+
+    #[test]
+    fn omega_phi_kappa_conventions() {
+        // Nadir view with north at the image top
+        assert_orientation(
+            omega_phi_kappa(0.0, 0.0, 0.0),
+            [0.0, 0.0, -1.0],
+            [0.0, 1.0, 0.0],
+        );
+        // Omega about the east axis tilts the view to the north, phi about the north axis to
+        // the west
+        let (sin, cos) = 10f32.to_radians().sin_cos();
+        assert_orientation(
+            omega_phi_kappa(10f32.to_radians(), 0.0, 0.0),
+            [0.0, sin, -cos],
+            [0.0, cos, sin],
+        );
+        assert_orientation(
+            omega_phi_kappa(0.0, 10f32.to_radians(), 0.0),
+            [-sin, 0.0, -cos],
+            [0.0, 1.0, 0.0],
+        );
+        // Kappa turns the image counterclockwise seen from above
+        assert_orientation(
+            omega_phi_kappa(0.0, 0.0, FRAC_PI_2),
+            [0.0, 0.0, -1.0],
+            [-1.0, 0.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn quaternion_and_look_at_conventions() {
+        // The camera frame has y down, so the identity looks up with north at the image bottom
+        assert_orientation(
+            quaternion(UnitQuaternion::identity()),
+            [0.0, 0.0, 1.0],
+            [0.0, -1.0, 0.0],
+        );
+
+        let position = Coords::new(2_600_000.0, 1_200_000.0, 500.0);
+        assert_orientation(
+            look_at(position, Coords::new(2_601_000.0, 1_200_000.0, 500.0)),
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+        );
+        assert_orientation(
+            look_at(position, Coords::new(2_600_000.0, 1_200_000.0, 0.0)),
+            [0.0, 0.0, -1.0],
+            [0.0, 1.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn mount_conventions() {
+        let body = Pose {
+            position: Coords::new(2_600_000.0, 1_200_000.0, 500.0),
+            forward: Vector3::y(),
+            up: Vector3::z(),
+        };
+        // Lever arm forward, right and down of a body heading north
+        let mount = Mount {
+            lever_arm_m: [1.0, 2.0, 3.0],
+            ..Mount::default()
+        };
+        let camera = mount.camera_pose(&body);
+        assert!((camera.position - Coords::new(2_600_002.0, 1_200_001.0, 497.0)).norm() < 1e-6);
+        assert_orientation(
+            (camera.forward, camera.up),
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        );
+
+        // Gimbal pitch down looks at the ground with the heading at the image top
+        let mount = Mount {
+            gimbal_pitch_rad: -FRAC_PI_2,
+            ..Mount::default()
+        };
+        let camera = mount.camera_pose(&body);
+        assert_orientation(
+            (camera.forward, camera.up),
+            [0.0, 0.0, -1.0],
+            [0.0, 1.0, 0.0],
+        );
+
+        // Gimbal yaw to the right, after the boresight yaw to the left
+        let mount = Mount {
+            gimbal_yaw_rad: FRAC_PI_2,
+            ..Mount::default()
+        };
+        let camera = mount.camera_pose(&body);
+        assert_orientation(
+            (camera.forward, camera.up),
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+        );
+        let mount = Mount {
+            boresight_rpy_rad: [0.0, 0.0, -FRAC_PI_2],
+            gimbal_yaw_rad: FRAC_PI_2,
+            ..Mount::default()
+        };
+        let camera = mount.camera_pose(&body);
+        assert_orientation(
+            (camera.forward, camera.up),
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        );
+    }
+}
@@ -1,10 +1,12 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::create_dir_all;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
 use clap::Parser;
-use image::DynamicImage;
 use itertools::Itertools;
 use log::{debug, info};
 use nalgebra::Vector3;
@@ -12,9 +14,19 @@ use rayon::iter::ParallelIterator;
 use rayon::prelude::IntoParallelIterator;
 use serde::{Deserialize, Serialize};
 
-use geo_renderer::camera::Intrinsics;
-use geo_renderer::config::StorageConfig;
+use geo_renderer::calibration::CalibrationConfig;
+use geo_renderer::camera::{Camera, Intrinsics};
+use geo_renderer::config::{ElevationSource, StorageConfig};
+use geo_renderer::cubemap::{Cubemap, CubemapPaths};
+use geo_renderer::depth::{DepthEncoding, DepthMapping, DepthMode, DepthSemantics};
+use geo_renderer::geodesy::Wgs84;
+use geo_renderer::geoid::{self, Geoid};
+use geo_renderer::landcover::{LabelClass, LABEL_CLASSES};
+use geo_renderer::normalmap::NormalMapPaths;
+use geo_renderer::pose::Mount;
 use geo_renderer::renderer::{RenderRequest, Renderer, RequestPose};
+use geo_renderer::rig::Rig;
+use geo_renderer::rolling_shutter::RollingShutter;
 use geo_renderer::Coords;
 
 #[derive(Parser)]
@@ -25,9 +37,62 @@ struct Flags {
     /// Minimum view distance to render in m, at most 100km
     #[clap(long)]
     view_range_m: f32,
+    /// Also render a level 360 degree panorama of this width at each pose, multiple of 64
+    #[clap(long)]
+    panorama_width_px: Option<u32>,
+    /// Also render the six faces of an LV95 aligned cubemap of this size at each pose, multiple
+    /// of 64
+    #[clap(long)]
+    cubemap_face_px: Option<u32>,
+    /// Bend the terrain for earth curvature with this refraction coefficient, 0.13 for a standard
+    /// atmosphere
+    #[clap(long)]
+    refraction_coefficient: Option<f32>,
+    /// How distances are stored in the depth buffer, the far plane of all modes is beyond the
+    /// view range and reverse_z and logarithmic stay precise at long ranges
+    #[clap(long, value_enum, default_value_t)]
+    depth_mode: DepthMode,
+    /// Whether the depth images hold the range along the ray or the z-depth along the optical
+    /// axis
+    #[clap(long, value_enum, default_value_t)]
+    depth_semantics: DepthSemantics,
+    /// Also store the LV95 position of the surface seen by each pixel
+    #[clap(long)]
+    world_coordinates: bool,
+    /// Also store the world and camera space normal, slope and aspect of the surface seen by
+    /// each pixel
+    #[clap(long)]
+    normals: bool,
+    /// Also store the land cover class of the surface seen by each pixel, needs --landcover-dir
+    #[clap(long)]
+    labels: bool,
+    /// Also store the height of the surface seen by each pixel above the bare terrain (nDSM),
+    /// from the difference of the surface and altitude models
+    #[clap(long)]
+    object_height: bool,
+    /// Simulate a rolling shutter with this time between the readout of two rows in seconds,
+    /// moving the camera with the optional velocity columns of the csv. Only constant linear and
+    /// angular velocity over the readout is implemented, there is no interpolation along a
+    /// trajectory.
+    #[clap(long)]
+    line_readout_s: Option<f32>,
+    /// Number of consecutive rows rendered with the same pose under a rolling shutter, each band
+    /// is a separate render pass. By default every row is rendered at its own readout time, more
+    /// rows per band trade accuracy for speed.
+    #[clap(long, default_value = "1")]
+    rows_per_band: u32,
+    /// Path to a toml rig definition whose cameras are additionally rendered at each pose
+    #[clap(long)]
+    rig_path: Option<PathBuf>,
+    /// Path to a toml camera mount, the poses of the csv are then the ones of the vehicle body
+    #[clap(long)]
+    mount_path: Option<PathBuf>,
     /// Folder where the data will be saved
     #[clap(long)]
     output_dir: PathBuf,
+    /// Camera calibration to render with
+    #[clap(flatten)]
+    calibration: CalibrationConfig,
     /// Paths to the swisstopo data
     #[clap(flatten)]
     storage_config: StorageConfig,
@@ -40,7 +105,11 @@ impl Flags {
     pub fn validate(&mut self) -> Result<()> {
         ensure!(self.camera_pose_csv_path.exists());
 
-        self.storage_config.validate()
+        self.storage_config.validate()?;
+        if self.object_height {
+            self.storage_config.validate_object_height()?;
+        }
+        Ok(())
     }
 }
 
@@ -48,13 +117,13 @@ impl Flags {
 struct LV95Coords {
     /// North coordinate to render in LV95
     #[clap(long)]
-    easting_m: f32,
+    easting_m: f64,
     /// East coordinate to render in LV95
     #[clap(long)]
-    northing_m: f32,
+    northing_m: f64,
     /// Altitude above ground level to render, in meters
     #[clap(long)]
-    altitude_m: f32,
+    altitude_m: f64,
 }
 
 impl From<LV95Coords> for Coords {
@@ -77,6 +146,79 @@ impl From<Coords> for LV95Coords {
 struct Image {
     rgb_image_path: PathBuf,
     depth_image_path: PathBuf,
+    /// Valid-pixel mask and bearing table of the intrinsics of this image, shared by all images
+    /// with the same intrinsics
+    mask_image_path: PathBuf,
+    bearing_table_path: PathBuf,
+    /// LV95 position of the surface seen by each pixel minus world_origin_lv95, float32 of shape
+    /// (height, width, 3), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    world_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    world_origin_lv95: Option<LV95Coords>,
+    /// Float32 normals of shape (height, width, 3) and slope and aspect in degrees of shape
+    /// (height, width), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normal_images: Option<NormalMapPaths>,
+    /// Class id of each pixel, see label_classes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_image_path: Option<PathBuf>,
+    /// Float32 height above the bare terrain of shape (height, width), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    object_height_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_rgb_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_depth_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cubemap_images: Option<CubemapPaths>,
+    camera_pos_lv95: LV95Coords,
+    camera_pos_wgs84: Wgs84,
+    depth_mapping: DepthMapping,
+    camera_forward: [f32; 3],
+    camera_up: [f32; 3],
+    /// Intrinsics of this image, if they differ from the ones of the dataset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    intrinsics: Option<Intrinsics>,
+    /// Pose of the vehicle body, if the camera is mounted on it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_pos_lv95: Option<LV95Coords>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_pos_wgs84: Option<Wgs84>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_forward: Option<[f32; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_up: Option<[f32; 3]>,
+    /// Mount of this image, if the camera is mounted on the body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mount: Option<Mount>,
+    /// Pose of each image row, if rendered with a rolling shutter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row_poses: Option<Vec<RowPose>>,
+    /// Images of the rig cameras in the order of the rig definition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rig_images: Option<Vec<RigImage>>,
+    /// Float32 disparity maps in the order of the stereo pairs of the rig definition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disparity_image_paths: Option<Vec<PathBuf>>,
+}
+
+#[derive(Serialize)]
+struct RigImage {
+    name: String,
+    rgb_image_path: PathBuf,
+    depth_image_path: PathBuf,
+    camera_pos_lv95: LV95Coords,
+    camera_forward: [f32; 3],
+    camera_up: [f32; 3],
+}
+
+#[derive(Serialize)]
+struct RowPose {
+    /// Time since the readout of the first row started
+    time_s: f32,
+    /// Time the pose of the band of the row was evaluated at, see --rows-per-band
+    band_time_s: f32,
     camera_pos_lv95: LV95Coords,
     camera_forward: [f32; 3],
     camera_up: [f32; 3],
@@ -86,24 +228,148 @@ struct Image {
 struct RenderedDataset {
     images: Vec<Image>,
     intrinsics: Intrinsics,
+    /// Valid-pixel mask of the dataset intrinsics
+    mask_image_path: PathBuf,
+    /// Unit ray per pixel in the camera frame of the dataset intrinsics, float32 of shape
+    /// (height, width, 3)
+    bearing_table_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_intrinsics: Option<Intrinsics>,
+    /// Intrinsics of the cubemap faces, which look along +E, -E, +N, -N, +U and -U
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cubemap_intrinsics: Option<Intrinsics>,
+    /// Refraction coefficient if the terrain was bent for earth curvature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refraction_coefficient: Option<f32>,
+    depth_encoding: DepthEncoding,
+    /// Elevation model the terrain was built from
+    elevation_source: ElevationSource,
+    /// Classes of the label images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_classes: Option<&'static [LabelClass]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rig: Option<Rig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rolling_shutter: Option<RollingShutterModel>,
+}
+
+/// How the row poses of the images were simulated
+#[derive(Serialize)]
+struct RollingShutterModel {
+    line_readout_s: f32,
+    /// Consecutive rows rendered with the pose at the readout of their center row
+    rows_per_band: u32,
+    /// Always constant_velocity, the camera moves with the velocities of its csv row and is not
+    /// interpolated along the trajectory of the csv
+    motion: &'static str,
+}
+
+/// File names of the valid-pixel mask and bearing table of one set of intrinsics
+#[derive(Clone)]
+struct CameraTables {
+    mask_image_path: PathBuf,
+    bearing_table_path: PathBuf,
+}
+
+impl CameraTables {
+    /// Write the mask and bearing table of the intrinsics to the output dir, adding the suffix
+    /// to their file names
+    fn write(intrinsics: &Intrinsics, output_dir: &Path, suffix: &str) -> Result<Self> {
+        let camera = Camera::new(Coords::origin(), intrinsics.clone());
+        let mask_image_path = PathBuf::from(format!("mask{}.png", suffix));
+        camera
+            .valid_mask()
+            .save(output_dir.join(&mask_image_path))?;
+        let bearing_table_path = PathBuf::from(format!("bearings{}.bin", suffix));
+        let bearing_table = camera.bearing_table();
+        let bearing_bin: &[u8] = bytemuck::cast_slice(bearing_table.as_slice().unwrap());
+        std::fs::write(output_dir.join(&bearing_table_path), bearing_bin)?;
+        Ok(Self {
+            mask_image_path,
+            bearing_table_path,
+        })
+    }
 }
 
 #[derive(Deserialize)]
 struct PoseCsvRecord {
-    cam_pos_lv95_e: f32,
-    cam_pos_lv95_n: f32,
-    cam_pos_lv95_u: f32,
+    /// Position in LV95, or alternatively in WGS84 with the ellipsoidal height, which is converted
+    /// with the geoid of the storage config if given
+    #[serde(default)]
+    cam_pos_lv95_e: Option<f64>,
+    #[serde(default)]
+    cam_pos_lv95_n: Option<f64>,
+    #[serde(default)]
+    cam_pos_lv95_u: Option<f64>,
+    #[serde(default)]
+    cam_pos_wgs84_lat_deg: Option<f64>,
+    #[serde(default)]
+    cam_pos_wgs84_lon_deg: Option<f64>,
+    #[serde(default)]
+    cam_pos_wgs84_h_m: Option<f64>,
     cam_fwd_lv95_e: f32,
     cam_fwd_lv95_n: f32,
     cam_fwd_lv95_u: f32,
     cam_up_lv95_e: f32,
     cam_up_lv95_n: f32,
     cam_up_lv95_u: f32,
+    /// Velocity in m/s, only used with a rolling shutter
+    #[serde(default)]
+    cam_vel_lv95_e: f32,
+    #[serde(default)]
+    cam_vel_lv95_n: f32,
+    #[serde(default)]
+    cam_vel_lv95_u: f32,
+    /// Angular velocity in the camera frame (x right, y down, z forward) in rad/s, only used with
+    /// a rolling shutter
+    #[serde(default)]
+    cam_angvel_x: f32,
+    #[serde(default)]
+    cam_angvel_y: f32,
+    #[serde(default)]
+    cam_angvel_z: f32,
+    /// Calibration of this image relative to the csv, in a format detected from the file,
+    /// defaults to --calibration-path
+    #[serde(default)]
+    camera_params_path: Option<PathBuf>,
+    /// Gimbal angles of the camera mount in rad, the pose is then the one of the vehicle body
+    #[serde(default)]
+    gimbal_yaw_rad: Option<f32>,
+    #[serde(default)]
+    gimbal_pitch_rad: Option<f32>,
+}
+
+impl PoseCsvRecord {
+    fn position(&self, geoid: Option<&Geoid>) -> Result<Coords> {
+        match (
+            self.cam_pos_lv95_e,
+            self.cam_pos_lv95_n,
+            self.cam_pos_lv95_u,
+            self.cam_pos_wgs84_lat_deg,
+            self.cam_pos_wgs84_lon_deg,
+            self.cam_pos_wgs84_h_m,
+        ) {
+            (Some(easting_m), Some(northing_m), Some(altitude_m), None, None, None) => {
+                Ok(Coords::new(easting_m, northing_m, altitude_m))
+            }
+            (None, None, None, Some(latitude_deg), Some(longitude_deg), Some(height_m)) => {
+                geoid::wgs84_to_lv95(
+                    &Wgs84 {
+                        latitude_deg,
+                        longitude_deg,
+                        height_m,
+                    },
+                    geoid,
+                )
+            }
+            _ => bail!("Either the LV95 or the WGS84 position columns have to be given"),
+        }
+    }
 }
 
 async fn run(mut args: Flags) -> Result<()> {
     args.validate()?;
-    let intrinsics = Intrinsics::load("camera_params.toml")?;
+    let intrinsics = args.calibration.load()?;
     create_dir_all(&args.output_dir)?;
     let image_json_path = args.output_dir.join("images.json");
     if image_json_path.exists() {
@@ -111,21 +377,95 @@ async fn run(mut args: Flags) -> Result<()> {
         return Ok(());
     }
     let mut state = Renderer::new(intrinsics.clone()).await;
+    let panorama_intrinsics = args.panorama_width_px.map(Intrinsics::equirectangular);
+    if let Some(panorama_intrinsics) = &panorama_intrinsics {
+        state.enable_panorama(panorama_intrinsics.clone())?;
+    }
+    if let Some(cubemap_face_px) = args.cubemap_face_px {
+        state.enable_cubemap(cubemap_face_px)?;
+    }
+    if let Some(refraction_coefficient) = args.refraction_coefficient {
+        state.enable_earth_curvature(refraction_coefficient);
+    }
+    state.set_depth_mode(args.depth_mode);
+    state.set_depth_semantics(args.depth_semantics);
+    if args.world_coordinates {
+        state.enable_world_coordinates();
+    }
+    if args.normals {
+        state.enable_normals();
+    }
+    if args.labels {
+        state.enable_labels();
+    }
+    if args.object_height {
+        state.enable_object_height();
+    }
+
+    let rig = args
+        .rig_path
+        .as_ref()
+        .map(Rig::load)
+        .transpose()?
+        .map(Arc::new);
+    let mount = args.mount_path.as_ref().map(Mount::load).transpose()?;
+    let geoid = args
+        .storage_config
+        .geoid_path
+        .as_ref()
+        .map(Geoid::load)
+        .transpose()?;
 
     let csv_records: Vec<PoseCsvRecord> = csv::Reader::from_path(&args.camera_pose_csv_path)?
         .deserialize()
         .into_iter()
         .collect::<Result<Vec<PoseCsvRecord>, _>>()?;
+    let csv_dir = args.camera_pose_csv_path.parent().unwrap_or(Path::new(""));
+    let camera_tables = CameraTables::write(&intrinsics, &args.output_dir, "")?;
+    let mut loaded_intrinsics: HashMap<PathBuf, (Intrinsics, CameraTables)> = HashMap::new();
+    let mut image_intrinsics: Vec<Option<Intrinsics>> = Vec::new();
+    let mut image_camera_tables: Vec<CameraTables> = Vec::new();
+    let mut image_mounts: Vec<Option<Mount>> = Vec::new();
+    let mut image_positions: Vec<Coords> = Vec::new();
+    for record in &csv_records {
+        image_positions.push(record.position(geoid.as_ref())?);
+        let loaded_count = loaded_intrinsics.len();
+        let (intrinsics, tables) = match &record.camera_params_path {
+            Some(path) => match loaded_intrinsics.entry(csv_dir.join(path)) {
+                Entry::Occupied(entry) => {
+                    let (intrinsics, tables) = entry.get();
+                    (Some(intrinsics.clone()), tables.clone())
+                }
+                Entry::Vacant(entry) => {
+                    let intrinsics = Intrinsics::load_calibration(entry.key(), None, None)?;
+                    let suffix = format!("_{}", loaded_count + 1);
+                    let tables = CameraTables::write(&intrinsics, &args.output_dir, &suffix)?;
+                    let (intrinsics, tables) = entry.insert((intrinsics, tables));
+                    (Some(intrinsics.clone()), tables.clone())
+                }
+            },
+            None => (None, camera_tables.clone()),
+        };
+        image_intrinsics.push(intrinsics);
+        image_camera_tables.push(tables);
+        image_mounts.push(
+            match (mount, record.gimbal_yaw_rad, record.gimbal_pitch_rad) {
+                (None, None, None) => None,
+                (mount, gimbal_yaw_rad, gimbal_pitch_rad) => {
+                    let mut mount = mount.unwrap_or_default();
+                    mount.gimbal_yaw_rad = gimbal_yaw_rad.unwrap_or(mount.gimbal_yaw_rad);
+                    mount.gimbal_pitch_rad = gimbal_pitch_rad.unwrap_or(mount.gimbal_pitch_rad);
+                    Some(mount)
+                }
+            },
+        );
+    }
     let render_requests = csv_records
         .into_iter()
         .enumerate()
         .map(|(id, record)| RenderRequest {
             camera_pose: RequestPose::FacingAsl {
-                camera_pos_asl: Coords::new(
-                    record.cam_pos_lv95_e,
-                    record.cam_pos_lv95_n,
-                    record.cam_pos_lv95_u,
-                ),
+                camera_pos_asl: image_positions[id],
                 camera_fwd: Vector3::<f32>::new(
                     record.cam_fwd_lv95_e,
                     record.cam_fwd_lv95_n,
@@ -137,6 +477,23 @@ async fn run(mut args: Flags) -> Result<()> {
                     record.cam_up_lv95_u,
                 ),
             },
+            rolling_shutter: args.line_readout_s.map(|line_readout_s| RollingShutter {
+                line_readout_s,
+                velocity_mps: Vector3::new(
+                    record.cam_vel_lv95_e,
+                    record.cam_vel_lv95_n,
+                    record.cam_vel_lv95_u,
+                ),
+                angular_velocity_radps: Vector3::new(
+                    record.cam_angvel_x,
+                    record.cam_angvel_y,
+                    record.cam_angvel_z,
+                ),
+                rows_per_band: args.rows_per_band,
+            }),
+            rig: rig.clone(),
+            intrinsics: image_intrinsics[id].clone(),
+            mount: image_mounts[id],
             request_id: id as u32,
         });
     let mut images: Vec<Image> = Vec::new();
@@ -155,31 +512,162 @@ async fn run(mut args: Flags) -> Result<()> {
         images.extend(
             rendered_requests
                 .into_par_iter()
-                .map(|request| {
+                .map(|request| -> Result<Image> {
                     let filename = args
                         .output_dir
                         .join(format!("image_{}", request.request_id));
                     let rgb_image_path = filename.with_extension("png");
                     let depth_image_path = filename.with_extension("bin");
 
-                    let image_rgba = DynamicImage::ImageRgba8(request.image_rgba);
-                    image_rgba.save(&rgb_image_path).unwrap();
+                    request.image_rgba.save(&rgb_image_path)?;
 
                     let depth_bin: &[u8] = bytemuck::cast_slice(&request.image_depth);
-                    std::fs::write(&depth_image_path, depth_bin).unwrap();
+                    std::fs::write(&depth_image_path, depth_bin)?;
 
-                    Image {
+                    let outputs = request.save_outputs(&filename)?;
+
+                    let rig_images = match (&rig, request.rig_images) {
+                        (Some(rig), Some(rig_images)) => Some(
+                            rig.cameras
+                                .iter()
+                                .zip(rig_images)
+                                .map(|(rig_camera, rig_image)| -> Result<RigImage> {
+                                    let filename = args.output_dir.join(format!(
+                                        "image_{}_{}",
+                                        request.request_id, rig_camera.name
+                                    ));
+                                    let rgb_path = filename.with_extension("png");
+                                    let depth_path = filename.with_extension("bin");
+                                    rig_image.image_rgba.save(&rgb_path)?;
+                                    let depth_bin: &[u8] =
+                                        bytemuck::cast_slice(&rig_image.image_depth);
+                                    std::fs::write(&depth_path, depth_bin)?;
+                                    Ok(RigImage {
+                                        name: rig_camera.name.clone(),
+                                        rgb_image_path: PathBuf::from(
+                                            rgb_path.file_name().expect(""),
+                                        ),
+                                        depth_image_path: PathBuf::from(
+                                            depth_path.file_name().expect(""),
+                                        ),
+                                        camera_pos_lv95: rig_image.camera_pos_lv95.into(),
+                                        camera_forward: rig_image
+                                            .camera_forward
+                                            .as_slice()
+                                            .try_into()
+                                            .unwrap(),
+                                        camera_up: rig_image
+                                            .camera_up
+                                            .as_slice()
+                                            .try_into()
+                                            .unwrap(),
+                                    })
+                                })
+                                .collect::<Result<_>>()?,
+                        ),
+                        _ => None,
+                    };
+                    let disparity_image_paths = match (&rig, request.rig_disparities) {
+                        (Some(rig), Some(rig_disparities)) => Some(
+                            rig.stereo_pairs
+                                .iter()
+                                .zip(rig_disparities)
+                                .map(|(pair, disparity)| -> Result<PathBuf> {
+                                    let disparity_path = args.output_dir.join(format!(
+                                        "image_{}_{}_{}_disparity.bin",
+                                        request.request_id,
+                                        rig.cameras[pair.left].name,
+                                        rig.cameras[pair.right].name
+                                    ));
+                                    let disparity_bin: &[u8] = bytemuck::cast_slice(&disparity);
+                                    std::fs::write(&disparity_path, disparity_bin)?;
+                                    Ok(PathBuf::from(disparity_path.file_name().expect("")))
+                                })
+                                .collect::<Result<_>>()?,
+                        ),
+                        _ => None,
+                    };
+
+                    Ok(Image {
                         rgb_image_path: PathBuf::from(rgb_image_path.file_name().expect("")),
                         depth_image_path: PathBuf::from(depth_image_path.file_name().expect("")),
+                        mask_image_path: image_camera_tables[request.request_id as usize]
+                            .mask_image_path
+                            .clone(),
+                        bearing_table_path: image_camera_tables[request.request_id as usize]
+                            .bearing_table_path
+                            .clone(),
+                        world_image_path: outputs.world_image_path,
+                        world_origin_lv95: request
+                            .image_world
+                            .as_ref()
+                            .map(|world| world.origin.into()),
+                        normal_images: outputs.normal_images,
+                        label_image_path: outputs.label_image_path,
+                        object_height_image_path: outputs.object_height_image_path,
+                        panorama_rgb_image_path: outputs.panorama_rgb_image_path,
+                        panorama_depth_image_path: outputs.panorama_depth_image_path,
+                        cubemap_images: outputs.cubemap_images,
                         camera_pos_lv95: request.camera_pos_lv95.into(),
+                        camera_pos_wgs84: geoid::lv95_to_wgs84(
+                            request.camera_pos_lv95,
+                            geoid.as_ref(),
+                        )?,
+                        depth_mapping: request.depth_mapping,
                         camera_forward: request.camera_forward.as_slice().try_into().unwrap(),
                         camera_up: request.camera_up.as_slice().try_into().unwrap(),
-                    }
+                        intrinsics: image_intrinsics[request.request_id as usize].clone(),
+                        body_pos_lv95: request.body_pose.map(|pose| pose.position.into()),
+                        body_pos_wgs84: request
+                            .body_pose
+                            .map(|pose| geoid::lv95_to_wgs84(pose.position, geoid.as_ref()))
+                            .transpose()?,
+                        body_forward: request
+                            .body_pose
+                            .map(|pose| pose.forward.as_slice().try_into().unwrap()),
+                        body_up: request
+                            .body_pose
+                            .map(|pose| pose.up.as_slice().try_into().unwrap()),
+                        mount: image_mounts[request.request_id as usize],
+                        row_poses: request.row_poses.map(|row_poses| {
+                            row_poses
+                                .into_iter()
+                                .map(|pose| RowPose {
+                                    time_s: pose.time_s,
+                                    band_time_s: pose.band_time_s,
+                                    camera_pos_lv95: pose.position.into(),
+                                    camera_forward: pose.forward.as_slice().try_into().unwrap(),
+                                    camera_up: pose.up.as_slice().try_into().unwrap(),
+                                })
+                                .collect()
+                        }),
+                        rig_images,
+                        disparity_image_paths,
+                    })
                 })
-                .collect::<Vec<_>>(),
+                .collect::<Result<Vec<_>>>()?,
         );
     }
-    let dataset = RenderedDataset { images, intrinsics };
+    let dataset = RenderedDataset {
+        images,
+        intrinsics,
+        mask_image_path: camera_tables.mask_image_path,
+        bearing_table_path: camera_tables.bearing_table_path,
+        panorama_intrinsics,
+        cubemap_intrinsics: args.cubemap_face_px.map(Cubemap::face_intrinsics),
+        refraction_coefficient: args.refraction_coefficient,
+        depth_encoding: DepthEncoding::new(args.depth_semantics),
+        elevation_source: args.storage_config.elevation_source,
+        label_classes: args.labels.then_some(LABEL_CLASSES),
+        rig: rig.map(|rig| (*rig).clone()),
+        rolling_shutter: args
+            .line_readout_s
+            .map(|line_readout_s| RollingShutterModel {
+                line_readout_s,
+                rows_per_band: args.rows_per_band,
+                motion: "constant_velocity",
+            }),
+    };
     std::fs::write(image_json_path, serde_json::to_string_pretty(&dataset)?)?;
     Ok(())
 }
@@ -1,28 +1,70 @@
 use std::convert::TryInto;
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
-use image::DynamicImage;
 use nalgebra::Point3;
 
 use serde::Serialize;
-use geo_renderer::camera::Intrinsics;
-use geo_renderer::config::StorageConfig;
+use geo_renderer::calibration::CalibrationConfig;
+use geo_renderer::camera::{Camera, Intrinsics};
+use geo_renderer::config::{ElevationSource, StorageConfig};
+use geo_renderer::cubemap::{Cubemap, CubemapPaths};
+use geo_renderer::depth::{DepthEncoding, DepthMapping, DepthMode, DepthSemantics};
+use geo_renderer::geodesy::Wgs84;
+use geo_renderer::geoid::{self, Geoid};
+use geo_renderer::landcover::{LabelClass, LABEL_CLASSES};
+use geo_renderer::normalmap::NormalMapPaths;
 use geo_renderer::renderer::{RenderRequest, Renderer, RequestPose};
 use geo_renderer::Coords;
 
 #[derive(Parser)]
 struct Flags {
-    /// Coordinate to render in LV95
+    /// Coordinate to render in LV95 or WGS84
     #[clap(flatten)]
-    camera_pos: LV95Coords,
+    camera_pos: CameraPosition,
     /// Minimum view distance to render in m, at most 100km
     #[clap(long)]
     view_range_m: f32,
+    /// Also render a level 360 degree panorama of this width at each pose, multiple of 64
+    #[clap(long)]
+    panorama_width_px: Option<u32>,
+    /// Also render the six faces of an LV95 aligned cubemap of this size at each pose, multiple
+    /// of 64
+    #[clap(long)]
+    cubemap_face_px: Option<u32>,
+    /// Bend the terrain for earth curvature with this refraction coefficient, 0.13 for a standard
+    /// atmosphere
+    #[clap(long)]
+    refraction_coefficient: Option<f32>,
+    /// How distances are stored in the depth buffer, the far plane of all modes is beyond the
+    /// view range and reverse_z and logarithmic stay precise at long ranges
+    #[clap(long, value_enum, default_value_t)]
+    depth_mode: DepthMode,
+    /// Whether the depth images hold the range along the ray or the z-depth along the optical
+    /// axis
+    #[clap(long, value_enum, default_value_t)]
+    depth_semantics: DepthSemantics,
+    /// Also store the LV95 position of the surface seen by each pixel
+    #[clap(long)]
+    world_coordinates: bool,
+    /// Also store the world and camera space normal, slope and aspect of the surface seen by
+    /// each pixel
+    #[clap(long)]
+    normals: bool,
+    /// Also store the land cover class of the surface seen by each pixel, needs --landcover-dir
+    #[clap(long)]
+    labels: bool,
+    /// Also store the height of the surface seen by each pixel above the bare terrain (nDSM),
+    /// from the difference of the surface and altitude models
+    #[clap(long)]
+    object_height: bool,
     /// Path to store the image
     #[clap(long)]
     output: PathBuf,
+    /// Camera calibration to render with
+    #[clap(flatten)]
+    calibration: CalibrationConfig,
     /// Paths to the swisstopo data
     #[clap(flatten)]
     storage_config: StorageConfig,
@@ -31,17 +73,57 @@ struct Flags {
     debug: bool,
 }
 
-#[derive(Parser, Serialize)]
-struct LV95Coords {
+#[derive(Parser)]
+struct CameraPosition {
+    /// East coordinate to render in LV95
+    #[clap(long)]
+    easting_m: Option<f64>,
     /// North coordinate to render in LV95
     #[clap(long)]
-    easting_m: f32,
-    /// East coordinate to render in LV95
+    northing_m: Option<f64>,
+    /// WGS84 latitude to render, instead of the LV95 coordinates
+    #[clap(long)]
+    latitude_deg: Option<f64>,
+    /// WGS84 longitude to render, instead of the LV95 coordinates
     #[clap(long)]
-    northing_m: f32,
+    longitude_deg: Option<f64>,
     /// Altitude above ground level to render, in meters
     #[clap(long)]
-    altitude_m: f32,
+    altitude_m: f64,
+}
+
+impl CameraPosition {
+    fn agl(&self) -> Result<Coords> {
+        let (easting_m, northing_m) = match (
+            self.easting_m,
+            self.northing_m,
+            self.latitude_deg,
+            self.longitude_deg,
+        ) {
+            (Some(easting_m), Some(northing_m), None, None) => (easting_m, northing_m),
+            (None, None, Some(latitude_deg), Some(longitude_deg)) => {
+                let lv95 = Wgs84 {
+                    latitude_deg,
+                    longitude_deg,
+                    height_m: 0.0,
+                }
+                .to_lv95();
+                (lv95.x, lv95.y)
+            }
+            _ => bail!("Either easting and northing or latitude and longitude have to be given"),
+        };
+        Ok(Point3::new(easting_m, northing_m, self.altitude_m))
+    }
+}
+
+#[derive(Serialize)]
+struct LV95Coords {
+    /// North coordinate to render in LV95
+    easting_m: f64,
+    /// East coordinate to render in LV95
+    northing_m: f64,
+    /// Altitude above ground level to render, in meters
+    altitude_m: f64,
 }
 
 impl From<LV95Coords> for Coords {
@@ -64,7 +146,32 @@ impl From<Coords> for LV95Coords {
 struct Image {
     rgb_image_path: PathBuf,
     depth_image_path: PathBuf,
+    mask_image_path: PathBuf,
+    /// LV95 position of the surface seen by each pixel minus world_origin_lv95, float32 of shape
+    /// (height, width, 3), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    world_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    world_origin_lv95: Option<LV95Coords>,
+    /// Float32 normals of shape (height, width, 3) and slope and aspect in degrees of shape
+    /// (height, width), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normal_images: Option<NormalMapPaths>,
+    /// Class id of each pixel, see label_classes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_image_path: Option<PathBuf>,
+    /// Float32 height above the bare terrain of shape (height, width), NaN for sky
+    #[serde(skip_serializing_if = "Option::is_none")]
+    object_height_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_rgb_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_depth_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cubemap_images: Option<CubemapPaths>,
     camera_pos_lv95: LV95Coords,
+    camera_pos_wgs84: Wgs84,
+    depth_mapping: DepthMapping,
     camera_forward: [f32; 3],
     camera_up: [f32; 3],
 }
@@ -73,21 +180,67 @@ struct Image {
 struct RenderedDataset {
     images: Vec<Image>,
     intrinsics: Intrinsics,
+    /// Unit ray per pixel in the camera frame, float32 of shape (height, width, 3)
+    bearing_table_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panorama_intrinsics: Option<Intrinsics>,
+    /// Intrinsics of the cubemap faces, which look along +E, -E, +N, -N, +U and -U
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cubemap_intrinsics: Option<Intrinsics>,
+    /// Refraction coefficient if the terrain was bent for earth curvature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refraction_coefficient: Option<f32>,
+    depth_encoding: DepthEncoding,
+    /// Elevation model the terrain was built from
+    elevation_source: ElevationSource,
+    /// Classes of the label images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_classes: Option<&'static [LabelClass]>,
 }
 
 async fn run(args: Flags) -> Result<()> {
-    let intrinsics = Intrinsics::load("camera_params.toml")?;
+    let intrinsics = args.calibration.load()?;
     let mut state = Renderer::new(intrinsics.clone()).await;
+    let panorama_intrinsics = args.panorama_width_px.map(Intrinsics::equirectangular);
+    if let Some(panorama_intrinsics) = &panorama_intrinsics {
+        state.enable_panorama(panorama_intrinsics.clone())?;
+    }
+    if let Some(cubemap_face_px) = args.cubemap_face_px {
+        state.enable_cubemap(cubemap_face_px)?;
+    }
+    if let Some(refraction_coefficient) = args.refraction_coefficient {
+        state.enable_earth_curvature(refraction_coefficient);
+    }
+    state.set_depth_mode(args.depth_mode);
+    state.set_depth_semantics(args.depth_semantics);
+    if args.world_coordinates {
+        state.enable_world_coordinates();
+    }
+    if args.normals {
+        state.enable_normals();
+    }
+    if args.labels {
+        state.enable_labels();
+    }
+    if args.object_height {
+        state.enable_object_height();
+    }
 
-    let camera_pos = Point3::<f32>::new(
-        args.camera_pos.easting_m,
-        args.camera_pos.northing_m,
-        args.camera_pos.altitude_m,
-    );
+    let camera_pos = args.camera_pos.agl()?;
+    let geoid = args
+        .storage_config
+        .geoid_path
+        .as_ref()
+        .map(Geoid::load)
+        .transpose()?;
     let render_requests: Vec<RenderRequest> = vec![RenderRequest {
         camera_pose: RequestPose::PositionAgl {
             camera_pos_agl: camera_pos,
         },
+        rolling_shutter: None,
+        rig: None,
+        mount: None,
+        intrinsics: None,
         request_id: 0,
     }];
     let rendered_requests = state
@@ -96,26 +249,64 @@ async fn run(args: Flags) -> Result<()> {
 
     let images = rendered_requests
         .into_iter()
-        .map(|request| {
+        .map(|request| -> Result<Image> {
             let rgb_image_path = args.output.with_extension("png");
             let depth_image_path = args.output.with_extension("bin");
+            let mask_image_path = args.output.with_file_name(format!(
+                "{}_mask.png",
+                args.output.file_stem().expect("").to_string_lossy()
+            ));
 
-            let image_rgba = DynamicImage::ImageRgba8(request.image_rgba);
-            image_rgba.save(&rgb_image_path).unwrap();
+            request.image_rgba.save(&rgb_image_path)?;
 
             let depth_bin: &[u8] = bytemuck::cast_slice(&request.image_depth);
-            std::fs::write(&depth_image_path, depth_bin).unwrap();
+            std::fs::write(&depth_image_path, depth_bin)?;
 
-            Image {
+            request.image_mask.save(&mask_image_path)?;
+
+            let outputs = request.save_outputs(&args.output)?;
+
+            Ok(Image {
                 rgb_image_path: PathBuf::from(rgb_image_path.file_name().expect("")),
                 depth_image_path: PathBuf::from(depth_image_path.file_name().expect("")),
+                mask_image_path: PathBuf::from(mask_image_path.file_name().expect("")),
+                world_image_path: outputs.world_image_path,
+                world_origin_lv95: request
+                    .image_world
+                    .as_ref()
+                    .map(|world| world.origin.into()),
+                normal_images: outputs.normal_images,
+                label_image_path: outputs.label_image_path,
+                object_height_image_path: outputs.object_height_image_path,
+                panorama_rgb_image_path: outputs.panorama_rgb_image_path,
+                panorama_depth_image_path: outputs.panorama_depth_image_path,
+                cubemap_images: outputs.cubemap_images,
                 camera_pos_lv95: request.camera_pos_lv95.into(),
+                camera_pos_wgs84: geoid::lv95_to_wgs84(request.camera_pos_lv95, geoid.as_ref())?,
+                depth_mapping: request.depth_mapping,
                 camera_forward: request.camera_forward.as_slice().try_into().unwrap(),
                 camera_up: request.camera_up.as_slice().try_into().unwrap(),
-            }
+            })
         })
-        .collect();
-    let dataset = RenderedDataset { images, intrinsics };
+        .collect::<Result<_>>()?;
+    let bearing_table_path = args.output.with_file_name(format!(
+        "{}_bearings.bin",
+        args.output.file_stem().expect("").to_string_lossy()
+    ));
+    let bearing_table = Camera::new(Coords::origin(), intrinsics.clone()).bearing_table();
+    let bearing_bin: &[u8] = bytemuck::cast_slice(bearing_table.as_slice().unwrap());
+    std::fs::write(&bearing_table_path, bearing_bin)?;
+    let dataset = RenderedDataset {
+        images,
+        intrinsics,
+        bearing_table_path: PathBuf::from(bearing_table_path.file_name().expect("")),
+        panorama_intrinsics,
+        cubemap_intrinsics: args.cubemap_face_px.map(Cubemap::face_intrinsics),
+        refraction_coefficient: args.refraction_coefficient,
+        depth_encoding: DepthEncoding::new(args.depth_semantics),
+        elevation_source: args.storage_config.elevation_source,
+        label_classes: args.labels.then_some(LABEL_CLASSES),
+    };
     std::fs::write(
         args.output.with_extension("json"),
         serde_json::to_string_pretty(&dataset)?,
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use anyhow::{ensure, Result};
+use clap::Parser;
+use nalgebra::Point2;
+
+use geo_renderer::camera::Intrinsics;
+use geo_renderer::config::StorageConfig;
+use geo_renderer::renderer::Renderer;
+
+#[derive(Parser)]
+struct Flags {
+    /// West edge of the area to render in LV95
+    #[clap(long)]
+    min_easting_m: f64,
+    /// South edge of the area to render in LV95
+    #[clap(long)]
+    min_northing_m: f64,
+    /// East edge of the area to render in LV95
+    #[clap(long)]
+    max_easting_m: f64,
+    /// North edge of the area to render in LV95
+    #[clap(long)]
+    max_northing_m: f64,
+    /// Ground sampling distance in m per pixel
+    #[clap(long)]
+    gsd_m: f32,
+    /// Path to store the RGB GeoTIFF, the elevation is stored next to it with a _dsm suffix
+    #[clap(long)]
+    output: PathBuf,
+    /// Paths to the swisstopo data
+    #[clap(flatten)]
+    storage_config: StorageConfig,
+    /// Verbose printing
+    #[clap(long)]
+    debug: bool,
+}
+
+async fn run(args: Flags) -> Result<()> {
+    args.storage_config.validate()?;
+    ensure!(
+        args.min_easting_m < args.max_easting_m && args.min_northing_m < args.max_northing_m,
+        "Empty bounding box"
+    );
+    // The perspective camera of the renderer is not used for orthophotos
+    let state = Renderer::new(Intrinsics::orthographic(args.gsd_m, 1, 1)).await;
+    let orthophoto = state
+        .render_orthophoto(
+            Point2::new(args.min_easting_m, args.min_northing_m),
+            Point2::new(args.max_easting_m, args.max_northing_m),
+            args.gsd_m,
+            &args.storage_config,
+        )
+        .await?;
+
+    let rgb_image_path = args.output.with_extension("tif");
+    let elevation_image_path = args.output.with_file_name(format!(
+        "{}_dsm.tif",
+        args.output.file_stem().expect("").to_string_lossy()
+    ));
+    orthophoto.save_rgb(&rgb_image_path)?;
+    orthophoto.save_elevation(&elevation_image_path)?;
+    Ok(())
+}
+
+fn main() {
+    let args = Flags::parse();
+    let level = if args.debug {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    let colors = fern::colors::ColoredLevelConfig::new()
+        .debug(fern::colors::Color::Blue)
+        .info(fern::colors::Color::Green)
+        .error(fern::colors::Color::Red)
+        .warn(fern::colors::Color::Yellow);
+    fern::Dispatch::new()
+        .format(move |out, message, record| {
+            out.finish(format_args!(
+                "{} {} [{}] {}",
+                chrono::Local::now().format("[%Y-%m-%d %H:%M:%S:%f]"),
+                colors.color(record.level()),
+                record.target(),
+                message,
+            ))
+        })
+        .level(level)
+        .level_for("wgpu_core", log::LevelFilter::Warn)
+        .level_for("wgpu_hal", log::LevelFilter::Warn)
+        .chain(std::io::stdout())
+        .apply()
+        .unwrap();
+    if let Err(err) = pollster::block_on(run(args)) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -1,19 +1,42 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
 use std::num::NonZeroU32;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::Result;
-use image::{ImageBuffer, Rgba};
+use anyhow::{ensure, Context, Result};
+use image::{GrayImage, ImageBuffer, Luma, Rgba};
 use itertools::Itertools;
 use log::info;
-use nalgebra::Vector3;
+use nalgebra::{Point2, UnitQuaternion, Vector3};
 use wgpu::util::DeviceExt;
 
-use crate::camera::{Camera, CameraUniform, Intrinsics};
+use crate::camera::{Camera, CameraUniform, Intrinsics, ProjectionModel};
 use crate::config::StorageConfig;
-use crate::gridsquare::{GridCoords, GridSquare};
-use crate::model::{DrawModel, Model, Vertex};
-use crate::terraingrid::TerrainGrid;
+use crate::cubemap::{Cubemap, CubemapPaths, FACE_DIRECTIONS};
+use crate::depth::{DepthMapping, DepthMode, DepthSemantics};
+use crate::gridsquare::{GridCoords, GridSquare, LABEL_MATERIAL};
+use crate::landcover::LABEL_SKY;
+use crate::model::{DrawModel, Vertex};
+use crate::normalmap::{NormalMap, NormalMapPaths};
+use crate::orthophoto::Orthophoto;
+use crate::pose::{self, Mount, Pose};
+use crate::rig::Rig;
+use crate::rolling_shutter::{RollingShutter, RowPose};
+use crate::terraingrid::{TerrainGrid, TerrainModels};
+use crate::worldmap::WorldMap;
 use crate::{model, texture, Coords};
 
+/// Altitude of the orthographic camera, above the highest terrain in Switzerland
+const ORTHOPHOTO_CAMERA_ALTITUDE_M: f32 = 5_000.0;
+/// Rows of the readback buffers have to be aligned to 256 bytes, so images are rendered at
+/// widths that are a multiple of 64 pixels of 4 bytes
+pub const WIDTH_ALIGNMENT_PX: u32 = 64;
+
+/// Position and orientation of a requested image, see `pose` for the angle conventions
+///
+/// Poses without an orientation look straight down with north at the image bottom.
 #[derive(Debug, Copy, Clone)]
 pub enum RequestPose {
     PositionAgl {
@@ -27,22 +50,95 @@ pub enum RequestPose {
         camera_fwd: Vector3<f32>,
         camera_up: Vector3<f32>,
     },
+    /// Aviation yaw, pitch and roll of a forward looking camera, see `pose::yaw_pitch_roll_ned`
+    YawPitchRollAsl {
+        camera_pos_asl: Coords,
+        yaw_rad: f32,
+        pitch_rad: f32,
+        roll_rad: f32,
+    },
+    /// Rotation from the camera frame (x right, y down, z forward) to LV95
+    QuaternionAsl {
+        camera_pos_asl: Coords,
+        camera_to_lv95: UnitQuaternion<f32>,
+    },
+    /// Photogrammetric orientation, see `pose::omega_phi_kappa`
+    OmegaPhiKappaAsl {
+        camera_pos_asl: Coords,
+        omega_rad: f32,
+        phi_rad: f32,
+        kappa_rad: f32,
+    },
+    /// Level camera looking at a target point in LV95
+    LookAtAsl {
+        camera_pos_asl: Coords,
+        target_lv95: Coords,
+    },
 }
 
-impl From<RequestPose> for GridCoords {
-    fn from(pose: RequestPose) -> Self {
-        match pose {
+impl RequestPose {
+    /// Camera position, above ground for PositionAgl and above sea level otherwise
+    fn position(&self) -> Coords {
+        match *self {
             RequestPose::PositionAgl { camera_pos_agl } => camera_pos_agl,
-            RequestPose::PositionAsl { camera_pos_asl } => camera_pos_asl,
-            RequestPose::FacingAsl { camera_pos_asl, .. } => camera_pos_asl,
+            RequestPose::PositionAsl { camera_pos_asl }
+            | RequestPose::FacingAsl { camera_pos_asl, .. }
+            | RequestPose::YawPitchRollAsl { camera_pos_asl, .. }
+            | RequestPose::QuaternionAsl { camera_pos_asl, .. }
+            | RequestPose::OmegaPhiKappaAsl { camera_pos_asl, .. }
+            | RequestPose::LookAtAsl { camera_pos_asl, .. } => camera_pos_asl,
+        }
+    }
+
+    /// Forward and up vector of the camera in LV95
+    fn orientation(&self) -> (Vector3<f32>, Vector3<f32>) {
+        match *self {
+            RequestPose::PositionAgl { .. } | RequestPose::PositionAsl { .. } => {
+                (pose::NADIR.0.into(), pose::NADIR.1.into())
+            }
+            RequestPose::FacingAsl {
+                camera_fwd,
+                camera_up,
+                ..
+            } => (camera_fwd, camera_up),
+            RequestPose::YawPitchRollAsl {
+                yaw_rad,
+                pitch_rad,
+                roll_rad,
+                ..
+            } => pose::yaw_pitch_roll_ned(yaw_rad, pitch_rad, roll_rad),
+            RequestPose::QuaternionAsl { camera_to_lv95, .. } => pose::quaternion(camera_to_lv95),
+            RequestPose::OmegaPhiKappaAsl {
+                omega_rad,
+                phi_rad,
+                kappa_rad,
+                ..
+            } => pose::omega_phi_kappa(omega_rad, phi_rad, kappa_rad),
+            RequestPose::LookAtAsl {
+                camera_pos_asl,
+                target_lv95,
+            } => pose::look_at(camera_pos_asl, target_lv95),
         }
-        .into()
+    }
+}
+
+impl From<RequestPose> for GridCoords {
+    fn from(pose: RequestPose) -> Self {
+        pose.position().into()
     }
 }
 
 #[derive(Debug)]
 pub struct RenderRequest {
     pub camera_pose: RequestPose,
+    /// Camera motion during the readout, the pose is the one of the first row
+    pub rolling_shutter: Option<RollingShutter>,
+    /// Rig whose cameras are additionally rendered, the pose is the one of the rig body
+    pub rig: Option<Arc<Rig>>,
+    /// Camera of this request, the intrinsics of the renderer are used if not given
+    pub intrinsics: Option<Intrinsics>,
+    /// Mounting of the camera, the pose is then the one of the vehicle body
+    pub mount: Option<Mount>,
     pub request_id: u32,
 }
 
@@ -51,49 +147,48 @@ struct NormalizedRenderRequest {
     camera_pos_asl: Coords,
     camera_fwd: Vector3<f32>,
     camera_up: Vector3<f32>,
+    rolling_shutter: Option<RollingShutter>,
+    rig: Option<Arc<Rig>>,
+    intrinsics: Option<Intrinsics>,
+    body_pose: Option<Pose>,
     request_id: u32,
 }
 
 impl RenderRequest {
     fn normalize(self, grid_square: &GridSquare) -> NormalizedRenderRequest {
-        match self.camera_pose {
-            RequestPose::PositionAgl { camera_pos_agl } => NormalizedRenderRequest {
-                camera_pos_agl,
-                camera_pos_asl: Coords::new(
-                    camera_pos_agl.x,
-                    camera_pos_agl.y,
-                    camera_pos_agl.z + grid_square.sample_altitude(camera_pos_agl),
-                ),
-                camera_fwd: Vector3::new(0.0, 0.0, -1.0),
-                camera_up: Vector3::new(0.0, -1.0, 0.0),
-                request_id: self.request_id,
-            },
-            RequestPose::PositionAsl { camera_pos_asl } => NormalizedRenderRequest {
-                camera_pos_agl: Coords::new(
-                    camera_pos_asl.x,
-                    camera_pos_asl.y,
-                    camera_pos_asl.z - grid_square.sample_altitude(camera_pos_asl),
-                ),
-                camera_pos_asl,
-                camera_fwd: Vector3::new(0.0, 0.0, -1.0),
-                camera_up: Vector3::new(0.0, -1.0, 0.0),
-                request_id: self.request_id,
-            },
-            RequestPose::FacingAsl {
-                camera_pos_asl,
-                camera_fwd,
-                camera_up,
-            } => NormalizedRenderRequest {
-                camera_pos_agl: Coords::new(
-                    camera_pos_asl.x,
-                    camera_pos_asl.y,
-                    camera_pos_asl.z - grid_square.sample_altitude(camera_pos_asl),
-                ),
-                camera_pos_asl,
-                camera_fwd,
-                camera_up,
-                request_id: self.request_id,
-            },
+        let position = self.camera_pose.position();
+        let altitude_m = grid_square.sample_altitude(position);
+        let (pos_agl, pos_asl) = match self.camera_pose {
+            RequestPose::PositionAgl { .. } => (
+                position,
+                Coords::new(position.x, position.y, position.z + altitude_m as f64),
+            ),
+            _ => (
+                Coords::new(position.x, position.y, position.z - altitude_m as f64),
+                position,
+            ),
+        };
+        let (forward, up) = self.camera_pose.orientation();
+        let body_pose = Pose {
+            position: pos_asl,
+            forward,
+            up,
+        };
+        let camera_pose = match &self.mount {
+            Some(mount) => mount.camera_pose(&body_pose),
+            None => body_pose,
+        };
+        NormalizedRenderRequest {
+            // The lever arm is short, the terrain below the body is used for the camera as well
+            camera_pos_agl: pos_agl + (camera_pose.position - pos_asl),
+            camera_pos_asl: camera_pose.position,
+            camera_fwd: camera_pose.forward,
+            camera_up: camera_pose.up,
+            rolling_shutter: self.rolling_shutter,
+            rig: self.rig,
+            intrinsics: self.intrinsics,
+            body_pose: self.mount.map(|_| body_pose),
+            request_id: self.request_id,
         }
     }
 }
@@ -106,24 +201,367 @@ pub struct RenderedRequest {
     pub camera_up: Vector3<f32>,
     pub request_id: u32,
     pub image_rgba: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    /// Metric depth of each pixel with the semantics of the renderer, NaN for sky
+    pub image_depth: Vec<f32>,
+    /// Depth buffer mapping used for all images of this request, terrain beyond its far plane
+    /// is clipped
+    pub depth_mapping: DepthMapping,
+    /// Pixels of the image that correspond to a valid ray, 255 if valid
+    pub image_mask: ImageBuffer<Luma<u8>, Vec<u8>>,
+    /// LV95 position of the surface seen by each pixel of the image, if enabled
+    pub image_world: Option<WorldMap>,
+    /// Normal, slope and aspect of the surface seen by each pixel of the image, if enabled
+    pub image_normals: Option<NormalMap>,
+    /// Land cover class id of each pixel of the image (see landcover::LABEL_CLASSES), if enabled
+    pub image_labels: Option<GrayImage>,
+    /// Height of the surface seen by each pixel of the image above the bare terrain, NaN for
+    /// sky, if enabled
+    pub image_object_height: Option<Vec<f32>>,
+    /// Level equirectangular panorama at the camera position, if enabled
+    pub panorama_rgba: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    pub panorama_depth: Option<Vec<f32>>,
+    /// LV95 aligned cubemap at the camera position with range depth, if enabled
+    pub cubemap: Option<Cubemap>,
+    /// Pose of each image row, if rendered with a rolling shutter
+    pub row_poses: Option<Vec<RowPose>>,
+    /// Pose of the vehicle body, if the camera was mounted on it
+    pub body_pose: Option<Pose>,
+    /// Image of each rig camera in the order of Rig::cameras, if the request references a rig
+    pub rig_images: Option<Vec<RigImage>>,
+    /// Disparity in pixels of the left image for each stereo pair of the rig
+    pub rig_disparities: Option<Vec<Vec<f32>>>,
+}
+
+/// Rendering of one camera of a rig
+#[derive(Debug, Default)]
+pub struct RigImage {
+    pub camera_pos_lv95: Coords,
+    pub camera_forward: Vector3<f32>,
+    pub camera_up: Vector3<f32>,
+    pub image_rgba: ImageBuffer<Rgba<u8>, Vec<u8>>,
     pub image_depth: Vec<f32>,
 }
 
+/// File names of the optional outputs of a saved RenderedRequest, None if not rendered
+#[derive(Debug, Clone, Default)]
+pub struct OutputPaths {
+    /// Float32 offsets of shape (height, width, 3) from the origin of the world map
+    pub world_image_path: Option<PathBuf>,
+    pub normal_images: Option<NormalMapPaths>,
+    pub label_image_path: Option<PathBuf>,
+    /// Float32 of shape (height, width)
+    pub object_height_image_path: Option<PathBuf>,
+    pub panorama_rgb_image_path: Option<PathBuf>,
+    /// Float32 of shape (height, width)
+    pub panorama_depth_image_path: Option<PathBuf>,
+    pub cubemap_images: Option<CubemapPaths>,
+}
+
+impl RenderedRequest {
+    /// Write each optional output next to the given path, adding a suffix to its file name
+    pub fn save_outputs<P: AsRef<Path>>(&self, path: P) -> Result<OutputPaths> {
+        let path = path.as_ref();
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let file_name = |suffix: &str| PathBuf::from(format!("{}_{}", stem, suffix));
+        let write = |suffix: &str, data: &[f32]| -> Result<PathBuf> {
+            let file_name = file_name(suffix);
+            std::fs::write(path.with_file_name(&file_name), bytemuck::cast_slice(data))?;
+            Ok(file_name)
+        };
+        let mut paths = OutputPaths {
+            world_image_path: self
+                .image_world
+                .as_ref()
+                .map(|world| write("world.bin", &world.offsets_m))
+                .transpose()?,
+            normal_images: self
+                .image_normals
+                .as_ref()
+                .map(|normals| normals.save(path))
+                .transpose()?,
+            object_height_image_path: self
+                .image_object_height
+                .as_ref()
+                .map(|heights| write("object_height.bin", heights))
+                .transpose()?,
+            cubemap_images: self
+                .cubemap
+                .as_ref()
+                .map(|cubemap| cubemap.save(path))
+                .transpose()?,
+            ..Default::default()
+        };
+        if let Some(labels) = &self.image_labels {
+            let label_file_name = file_name("labels.png");
+            labels.save(path.with_file_name(&label_file_name))?;
+            paths.label_image_path = Some(label_file_name);
+        }
+        if let (Some(panorama_rgba), Some(panorama_depth)) =
+            (&self.panorama_rgba, &self.panorama_depth)
+        {
+            let rgb_file_name = file_name("panorama.png");
+            panorama_rgba.save(path.with_file_name(&rgb_file_name))?;
+            paths.panorama_rgb_image_path = Some(rgb_file_name);
+            paths.panorama_depth_image_path = Some(write("panorama.bin", panorama_depth)?);
+        }
+        Ok(paths)
+    }
+}
+
+/// Color, depth and additional output textures of one image size with their readback buffers
+struct RenderTarget {
+    size: wgpu::Extent3d,
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    /// Outputs the target was created with, the others have no texture
+    outputs: Outputs,
+    /// Position of the surface relative to the terrain origin
+    world: Option<OutputTarget>,
+    /// Normal of the surface in LV95 axes
+    normal: Option<OutputTarget>,
+    /// Land cover class id in the first channel
+    label: Option<OutputTarget>,
+    /// Height of the surface above the bare terrain
+    object_height: Option<OutputTarget>,
+    depth_texture: texture::Texture,
+    output_buffer: wgpu::Buffer,
+    depth_output_buffer: wgpu::Buffer,
+}
+
+/// Four channel texture of an additional per-pixel output with its readback buffer
+struct OutputTarget {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    output_buffer: wgpu::Buffer,
+    /// Size of a pixel in bytes
+    pixel_size: u32,
+}
+
+impl OutputTarget {
+    const FLOAT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+    const LABEL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+    const HEIGHT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+
+    fn new(
+        device: &wgpu::Device,
+        size: wgpu::Extent3d,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some(label),
+        });
+        let view = texture.create_view(&Default::default());
+        let pixel_size = format.describe().block_size as u32;
+        let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: (pixel_size * size.width * size.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: None,
+            mapped_at_creation: false,
+        });
+        Self {
+            texture,
+            view,
+            output_buffer,
+            pixel_size,
+        }
+    }
+
+    /// Map the readback buffer and copy out its contents
+    async fn read(&self, device: &wgpu::Device) -> Vec<u8> {
+        let buffer_slice = self.output_buffer.slice(..);
+        let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            tx.send(result).unwrap();
+        });
+        device.poll(wgpu::Maintain::Wait);
+        rx.receive().await.unwrap().unwrap();
+
+        let data = (*buffer_slice.get_mapped_range()).to_vec();
+        self.output_buffer.unmap();
+        data
+    }
+}
+
+/// Additional per-pixel outputs read back after drawing
+#[derive(Debug, Clone, Copy, Default)]
+struct Outputs {
+    world: bool,
+    normals: bool,
+    labels: bool,
+    object_height: bool,
+}
+
+impl Outputs {
+    /// Whether the color pass writes additional targets, labels are drawn in a pass of their own
+    fn in_color_pass(&self) -> bool {
+        self.world || self.normals || self.object_height
+    }
+
+    /// Whether all outputs of other are enabled here as well
+    fn contains(&self, other: Outputs) -> bool {
+        (self.world || !other.world)
+            && (self.normals || !other.normals)
+            && (self.labels || !other.labels)
+            && (self.object_height || !other.object_height)
+    }
+}
+
+/// Contents of a render target after drawing
+struct Frame {
+    rgba: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    /// Metric depth, see Renderer::draw
+    depth: Vec<f32>,
+    /// Position relative to the terrain origin as in WorldMap, if it was read back
+    world: Option<Vec<f32>>,
+    /// Normal in LV95 axes, if it was read back
+    normals: Option<Vec<f32>>,
+    /// Land cover class ids, if they were read back
+    labels: Option<GrayImage>,
+    /// Height above the bare terrain, if it was read back
+    object_height: Option<Vec<f32>>,
+}
+
+impl RenderTarget {
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    /// Target with the textures of the given outputs
+    fn new(device: &wgpu::Device, width: u32, height: u32, outputs: Outputs) -> Self {
+        let render_texture_desc = wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some("RenderTexture"),
+        };
+        let texture = device.create_texture(&render_texture_desc);
+        let view = texture.create_view(&Default::default());
+
+        let u32_size = std::mem::size_of::<u32>() as u32;
+
+        let output_buffer_size =
+            (u32_size * render_texture_desc.size.width * render_texture_desc.size.height)
+                as wgpu::BufferAddress;
+        let output_buffer_desc = wgpu::BufferDescriptor {
+            size: output_buffer_size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: None,
+            mapped_at_creation: false,
+        };
+        let output_buffer = device.create_buffer(&output_buffer_desc);
+
+        let depth_texture = texture::Texture::create_depth_texture(
+            device,
+            render_texture_desc.size,
+            "depth_texture",
+        );
+
+        let f32_size = std::mem::size_of::<f32>() as u32;
+        let depth_output_buffer_size =
+            (f32_size * render_texture_desc.size.width * render_texture_desc.size.height)
+                as wgpu::BufferAddress;
+        let depth_output_buffer_desc = wgpu::BufferDescriptor {
+            size: depth_output_buffer_size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: None,
+            mapped_at_creation: false,
+        };
+        let depth_output_buffer = device.create_buffer(&depth_output_buffer_desc);
+
+        Self {
+            size: render_texture_desc.size,
+            texture,
+            view,
+            outputs,
+            world: outputs.world.then(|| {
+                OutputTarget::new(
+                    device,
+                    render_texture_desc.size,
+                    OutputTarget::FLOAT_FORMAT,
+                    "WorldTexture",
+                )
+            }),
+            normal: outputs.normals.then(|| {
+                OutputTarget::new(
+                    device,
+                    render_texture_desc.size,
+                    OutputTarget::FLOAT_FORMAT,
+                    "NormalTexture",
+                )
+            }),
+            label: outputs.labels.then(|| {
+                OutputTarget::new(
+                    device,
+                    render_texture_desc.size,
+                    OutputTarget::LABEL_FORMAT,
+                    "LabelTexture",
+                )
+            }),
+            object_height: outputs.object_height.then(|| {
+                OutputTarget::new(
+                    device,
+                    render_texture_desc.size,
+                    OutputTarget::HEIGHT_FORMAT,
+                    "ObjectHeightTexture",
+                )
+            }),
+            depth_texture,
+            output_buffer,
+            depth_output_buffer,
+        }
+    }
+}
+
 pub struct Renderer {
     device: wgpu::Device,
     queue: wgpu::Queue,
     render_pipeline: wgpu::RenderPipeline,
+    /// Intrinsics of requests that do not bring their own
+    intrinsics: Intrinsics,
     camera: Camera,
-    camera_uniform: CameraUniform,
+    /// Valid pixel masks of all intrinsics rendered so far
+    masks: Vec<(Intrinsics, GrayImage)>,
+    /// Optical axis component of the pixel rays of all intrinsics converted to z-depth so far
+    axis_factors: Vec<(Intrinsics, Vec<f32>)>,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
     texture_bind_group_layout: wgpu::BindGroupLayout,
-    output_buffer: wgpu::Buffer,
-    depth_output_buffer: wgpu::Buffer,
-    render_texture_view: wgpu::TextureView,
-    render_texture_size: wgpu::Extent3d,
-    render_texture: wgpu::Texture,
-    depth_texture: texture::Texture,
+    /// Camera and target for the optional panorama rendered at each pose
+    panorama: Option<(Camera, RenderTarget)>,
+    /// Target for the optional cubemap faces rendered at each pose
+    cubemap: Option<RenderTarget>,
+    /// Targets of the request and rig cameras by image size, created on first use
+    targets: HashMap<(u32, u32), RenderTarget>,
+    /// Refraction coefficient if the terrain is bent for earth curvature
+    refraction_coefficient: Option<f32>,
+    /// Depth mode of the requests, the planes are chosen per request
+    depth_mode: DepthMode,
+    /// Whether the depth output of the requests is range or z-depth
+    depth_semantics: DepthSemantics,
+    /// Pipeline with a greater depth test for reversed depth modes
+    reverse_render_pipeline: wgpu::RenderPipeline,
+    /// Pipelines drawing the land cover labels over the depth of the color pass, the second one
+    /// for reversed depth modes
+    label_pipeline: wgpu::RenderPipeline,
+    reverse_label_pipeline: wgpu::RenderPipeline,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    /// Pipelines of the color pass also writing the enabled world, normal and object height
+    /// outputs, the second one for reversed depth modes
+    output_render_pipelines: Option<[wgpu::RenderPipeline; 2]>,
+    /// Additional outputs read back for the images of the requests
+    outputs: Outputs,
 }
 
 impl Renderer {
@@ -180,37 +618,8 @@ impl Renderer {
                 label: Some("texture_bind_group_layout"),
             });
 
-        let render_texture_desc = wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width: intrinsics.image_width_px,
-                height: intrinsics.image_height_px,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            label: Some("RenderTexture"),
-        };
-        let render_texture = device.create_texture(&render_texture_desc);
-        let render_texture_view = render_texture.create_view(&Default::default());
-
-        let u32_size = std::mem::size_of::<u32>() as u32;
-
-        let output_buffer_size =
-            (u32_size * render_texture_desc.size.width * render_texture_desc.size.height)
-                as wgpu::BufferAddress;
-        let output_buffer_desc = wgpu::BufferDescriptor {
-            size: output_buffer_size,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            label: None,
-            mapped_at_creation: false,
-        };
-        let output_buffer = device.create_buffer(&output_buffer_desc);
-
         // Camera
-        let camera = Camera::new(Coords::new(0.0, 0.0, 0.0), intrinsics);
+        let camera = Camera::new(Coords::new(0.0, 0.0, 0.0), intrinsics.clone());
         let camera_uniform = CameraUniform::new();
 
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -243,24 +652,6 @@ impl Renderer {
             label: Some("camera_bind_group"),
         });
 
-        let depth_texture = texture::Texture::create_depth_texture(
-            &device,
-            render_texture_desc.size,
-            "depth_texture",
-        );
-
-        let f32_size = std::mem::size_of::<f32>() as u32;
-        let depth_output_buffer_size =
-            (f32_size * render_texture_desc.size.width * render_texture_desc.size.height)
-                as wgpu::BufferAddress;
-        let depth_output_buffer_desc = wgpu::BufferDescriptor {
-            size: depth_output_buffer_size,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            label: None,
-            mapped_at_creation: false,
-        };
-        let depth_output_buffer = device.create_buffer(&depth_output_buffer_desc);
-
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
@@ -268,48 +659,263 @@ impl Renderer {
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = {
+        let [render_pipeline, reverse_render_pipeline] =
+            Self::create_render_pipelines(&device, &render_pipeline_layout, Outputs::default());
+        // Surfaces are drawn again at the depth written by the color pass, so the test is inclusive
+        let [label_pipeline, reverse_label_pipeline] = [
+            wgpu::CompareFunction::LessEqual,
+            wgpu::CompareFunction::GreaterEqual,
+        ]
+        .map(|depth_compare| {
             let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader"),
+                label: Some("Label Shader"),
                 source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
             };
-            Self::create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                render_texture_desc.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc()],
-                shader,
-            )
-        };
+            Self::create_label_pipeline(&device, &render_pipeline_layout, depth_compare, shader)
+        });
 
         Self {
             device,
             queue,
             render_pipeline,
+            intrinsics,
             camera,
-            camera_uniform,
+            masks: Vec::new(),
+            axis_factors: Vec::new(),
             camera_buffer,
             camera_bind_group,
             texture_bind_group_layout,
-            render_texture_view,
-            render_texture_size: render_texture_desc.size,
-            render_texture,
-            output_buffer,
-            depth_texture,
-            depth_output_buffer,
+            panorama: None,
+            cubemap: None,
+            targets: HashMap::new(),
+            refraction_coefficient: None,
+            depth_mode: DepthMode::default(),
+            depth_semantics: DepthSemantics::default(),
+            reverse_render_pipeline,
+            label_pipeline,
+            reverse_label_pipeline,
+            render_pipeline_layout,
+            output_render_pipelines: None,
+            outputs: Outputs::default(),
         }
     }
 
+    /// Size of the pooled target for images of the given intrinsics with the given outputs,
+    /// creating it on first use
+    fn target_size(&mut self, intrinsics: &Intrinsics, outputs: Outputs) -> Result<(u32, u32)> {
+        ensure!(
+            intrinsics.image_width_px.is_multiple_of(WIDTH_ALIGNMENT_PX),
+            "Image width of {} px is not a multiple of {} px",
+            intrinsics.image_width_px,
+            WIDTH_ALIGNMENT_PX
+        );
+        let size = (intrinsics.image_width_px, intrinsics.image_height_px);
+        // Targets created for fewer outputs lack their textures
+        if self
+            .targets
+            .get(&size)
+            .is_none_or(|target| !target.outputs.contains(outputs))
+        {
+            let target = RenderTarget::new(&self.device, size.0, size.1, outputs);
+            self.targets.insert(size, target);
+        }
+        Ok(size)
+    }
+
+    /// Valid pixel mask of the given intrinsics, computed on first use
+    fn mask(&mut self, intrinsics: &Intrinsics) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        if let Some((_, mask)) = self.masks.iter().find(|(known, _)| known == intrinsics) {
+            return mask.clone();
+        }
+        let mask = Camera::new(Coords::origin(), intrinsics.clone()).valid_mask();
+        self.masks.push((intrinsics.clone(), mask.clone()));
+        mask
+    }
+
+    /// Convert the range of each pixel to the depth semantics of the renderer
+    fn with_depth_semantics(&mut self, intrinsics: &Intrinsics, mut depth: Vec<f32>) -> Vec<f32> {
+        if self.depth_semantics == DepthSemantics::Range {
+            return depth;
+        }
+        let index = match self
+            .axis_factors
+            .iter()
+            .position(|(known, _)| known == intrinsics)
+        {
+            Some(index) => index,
+            None => {
+                let bearings = Camera::new(Coords::origin(), intrinsics.clone()).bearing_table();
+                let factors = bearings
+                    .index_axis(ndarray::Axis(2), 2)
+                    .iter()
+                    .copied()
+                    .collect();
+                self.axis_factors.push((intrinsics.clone(), factors));
+                self.axis_factors.len() - 1
+            }
+        };
+        for (depth, factor) in depth.iter_mut().zip(&self.axis_factors[index].1) {
+            *depth *= factor;
+        }
+        depth
+    }
+
+    /// Additionally render a level panorama with the given intrinsics at every pose
+    ///
+    /// The panorama is centered on the heading of the camera, or north for nadir views.
+    pub fn enable_panorama(&mut self, intrinsics: Intrinsics) -> Result<()> {
+        ensure!(
+            intrinsics.image_width_px.is_multiple_of(WIDTH_ALIGNMENT_PX),
+            "Panorama width has to be a multiple of {} px",
+            WIDTH_ALIGNMENT_PX
+        );
+        let target = RenderTarget::new(
+            &self.device,
+            intrinsics.image_width_px,
+            intrinsics.image_height_px,
+            Outputs::default(),
+        );
+        self.panorama = Some((Camera::new(Coords::new(0.0, 0.0, 0.0), intrinsics), target));
+        Ok(())
+    }
+
+    /// Additionally render the six 90 degree faces of a cubemap at every pose
+    ///
+    /// Use `Cubemap::resample` to derive images of arbitrary camera models from it.
+    pub fn enable_cubemap(&mut self, face_size_px: u32) -> Result<()> {
+        ensure!(
+            face_size_px.is_multiple_of(WIDTH_ALIGNMENT_PX),
+            "Cubemap face size has to be a multiple of {} px",
+            WIDTH_ALIGNMENT_PX
+        );
+        self.cubemap = Some(RenderTarget::new(
+            &self.device,
+            face_size_px,
+            face_size_px,
+            Outputs::default(),
+        ));
+        Ok(())
+    }
+
+    /// Bend the terrain down with the distance from the camera for earth curvature and refraction
+    ///
+    /// The depth output is the distance to the apparent, bent terrain. Orthophotos are always
+    /// rendered flat.
+    pub fn enable_earth_curvature(&mut self, refraction_coefficient: f32) {
+        self.refraction_coefficient = Some(refraction_coefficient);
+    }
+
+    /// Store distances in the depth buffer with the given mode, linear by default
+    pub fn set_depth_mode(&mut self, depth_mode: DepthMode) {
+        self.depth_mode = depth_mode;
+    }
+
+    /// Output the depth of the request, panorama and rig images as range or z-depth, range by
+    /// default
+    pub fn set_depth_semantics(&mut self, depth_semantics: DepthSemantics) {
+        self.depth_semantics = depth_semantics;
+    }
+
+    /// Additionally output the LV95 position of the surface seen by each pixel of the requests
+    pub fn enable_world_coordinates(&mut self) {
+        self.outputs.world = true;
+        self.update_output_render_pipelines();
+    }
+
+    /// Additionally output the world and camera space normal, slope and aspect of the surface
+    /// seen by each pixel of the requests
+    pub fn enable_normals(&mut self) {
+        self.outputs.normals = true;
+        self.update_output_render_pipelines();
+    }
+
+    /// Additionally output the land cover class of the surface seen by each pixel of the
+    /// requests, rasterized from the GeoJSON tiles in the landcover dir of the storage config
+    pub fn enable_labels(&mut self) {
+        self.outputs.labels = true;
+    }
+
+    /// Additionally output the height above the bare terrain (nDSM) of the surface seen by each
+    /// pixel of the requests, from the difference of the surface and terrain models
+    pub fn enable_object_height(&mut self) {
+        self.outputs.object_height = true;
+        self.update_output_render_pipelines();
+    }
+
+    /// Recreate the color pass pipelines writing the outputs enabled so far
+    fn update_output_render_pipelines(&mut self) {
+        self.output_render_pipelines = Some(Self::create_render_pipelines(
+            &self.device,
+            &self.render_pipeline_layout,
+            self.outputs,
+        ));
+    }
+
+    /// Color pass pipelines with a less and a greater depth test, writing the given outputs
+    fn create_render_pipelines(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        outputs: Outputs,
+    ) -> [wgpu::RenderPipeline; 2] {
+        [wgpu::CompareFunction::Less, wgpu::CompareFunction::Greater].map(|depth_compare| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Normal Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+            };
+            Self::create_render_pipeline(
+                device,
+                layout,
+                outputs,
+                Some(texture::Texture::DEPTH_FORMAT),
+                depth_compare,
+                &[model::ModelVertex::desc()],
+                shader,
+            )
+        })
+    }
+
+    /// Pipeline writing the color and the outputs of the color pass, without any of them the
+    /// fragment entry only has the color target
     fn create_render_pipeline(
         device: &wgpu::Device,
         layout: &wgpu::PipelineLayout,
-        color_format: wgpu::TextureFormat,
+        outputs: Outputs,
         depth_format: Option<wgpu::TextureFormat>,
+        depth_compare: wgpu::CompareFunction,
         vertex_layouts: &[wgpu::VertexBufferLayout],
         shader: wgpu::ShaderModuleDescriptor,
     ) -> wgpu::RenderPipeline {
         let shader = device.create_shader_module(shader);
+        // Float targets can not be blended
+        let output_target = |format| wgpu::ColorTargetState {
+            format,
+            blend: None,
+            write_mask: wgpu::ColorWrites::ALL,
+        };
+        let targets = [
+            Some(wgpu::ColorTargetState {
+                format: RenderTarget::FORMAT,
+                blend: Some(wgpu::BlendState {
+                    alpha: wgpu::BlendComponent::REPLACE,
+                    color: wgpu::BlendComponent::REPLACE,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            }),
+            outputs
+                .world
+                .then(|| output_target(OutputTarget::FLOAT_FORMAT)),
+            outputs
+                .normals
+                .then(|| output_target(OutputTarget::FLOAT_FORMAT)),
+            outputs
+                .object_height
+                .then(|| output_target(OutputTarget::HEIGHT_FORMAT)),
+        ];
+        let (entry_point, targets) = if outputs.in_color_pass() {
+            ("fs_main", &targets[..])
+        } else {
+            ("fs_color", &targets[..1])
+        };
 
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some(&format!("{:?}", shader)),
@@ -321,15 +927,8 @@ impl Renderer {
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: color_format,
-                    blend: Some(wgpu::BlendState {
-                        alpha: wgpu::BlendComponent::REPLACE,
-                        color: wgpu::BlendComponent::REPLACE,
-                    }),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
+                entry_point,
+                targets,
             }),
             primitive: wgpu::PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
@@ -343,7 +942,58 @@ impl Renderer {
             depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
                 format,
                 depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
+                depth_compare,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+
+    /// Pipeline writing the class id of the label material into the first channel of the target
+    fn create_label_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        depth_compare: wgpu::CompareFunction,
+        shader: wgpu::ShaderModuleDescriptor,
+    ) -> wgpu::RenderPipeline {
+        let shader = device.create_shader_module(shader);
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&format!("{:?}", shader)),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[model::ModelVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_label",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: OutputTarget::LABEL_FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare,
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
@@ -363,6 +1013,13 @@ impl Renderer {
         view_range_m: f32,
         storage_config: &StorageConfig,
     ) -> Result<Vec<RenderedRequest>> {
+        ensure!(
+            !self.outputs.labels || storage_config.landcover_dir.is_some(),
+            "Labels need a landcover dir"
+        );
+        if self.outputs.object_height {
+            storage_config.validate_object_height()?;
+        }
         let camera_positions = render_requests
             .into_iter()
             .map(|req| -> (GridCoords, RenderRequest) { (req.camera_pose.into(), req) })
@@ -375,22 +1032,51 @@ impl Renderer {
                 .map(|r| r.normalize(&grid_square))
                 .collect();
             chunk_requests.sort_by(|p1, p2| p1.camera_pos_agl.z.total_cmp(&p2.camera_pos_agl.z));
-            let mut models = Vec::new();
+            // The terrain is loaded at the finest resolution any camera of the chunk needs,
+            // including the panorama and cubemap drawn at every pose
+            let mut chunk_intrinsics: Vec<Intrinsics> = self
+                .panorama
+                .iter()
+                .map(|(camera, _)| camera.intrinsics.clone())
+                .chain(
+                    self.cubemap
+                        .iter()
+                        .map(|target| Cubemap::face_intrinsics(target.size.width)),
+                )
+                .collect();
+            for request in &chunk_requests {
+                let rig_intrinsics = request
+                    .rig
+                    .iter()
+                    .flat_map(|rig| rig.cameras.iter().map(|camera| &camera.intrinsics));
+                let intrinsics = request.intrinsics.as_ref().unwrap_or(&self.intrinsics);
+                for intrinsics in std::iter::once(intrinsics).chain(rig_intrinsics) {
+                    if !chunk_intrinsics.contains(intrinsics) {
+                        chunk_intrinsics.push(intrinsics.clone());
+                    }
+                }
+            }
+            let mut terrain = TerrainModels {
+                origin: grid_coords.into(),
+                models: Vec::new(),
+            };
             let mut agl_m = -1000.0;
             for render_request in chunk_requests {
-                if render_request.camera_pos_agl.z > 1.5 * agl_m {
-                    agl_m = render_request.camera_pos_agl.z;
-                    models = TerrainGrid::new(
+                if render_request.camera_pos_agl.z as f32 > 1.5 * agl_m {
+                    agl_m = render_request.camera_pos_agl.z as f32;
+                    terrain = TerrainGrid::new(
                         grid_coords,
                         agl_m,
-                        &self.camera,
+                        &chunk_intrinsics,
                         view_range_m,
                         storage_config,
+                        self.outputs.object_height,
                     )
                     .models(
                         &self.device,
                         &self.queue,
                         &self.texture_bind_group_layout,
+                        self.outputs.labels,
                     );
                 }
                 info!(
@@ -400,16 +1086,14 @@ impl Renderer {
                     render_request.camera_pos_agl.z,
                     agl_m
                 );
+                let depth_mapping = DepthMapping::new(
+                    self.depth_mode,
+                    render_request.camera_pos_agl.z as f32,
+                    view_range_m,
+                );
                 rendered_requests.push(
-                    self.render_image(
-                        render_request.camera_pos_agl,
-                        render_request.camera_pos_asl,
-                        render_request.camera_fwd,
-                        render_request.camera_up,
-                        render_request.request_id,
-                        &models,
-                    )
-                    .await?,
+                    self.render_image(render_request, &terrain, depth_mapping)
+                        .await?,
                 );
             }
         }
@@ -417,107 +1101,540 @@ impl Renderer {
         Ok(rendered_requests)
     }
 
-    pub async fn render_image(
+    async fn render_image(
         &mut self,
-        camera_pos_agl: Coords,
-        camera_pos_asl: Coords,
-        camera_fwd_lv95: Vector3<f32>,
-        camera_up_lv95: Vector3<f32>,
-        request_id: u32,
-        models: &Vec<Model>,
+        request: NormalizedRenderRequest,
+        terrain: &TerrainModels,
+        depth_mapping: DepthMapping,
     ) -> Result<RenderedRequest> {
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let camera_pos_asl = request.camera_pos_asl;
+        let camera_fwd_lv95 = request.camera_fwd;
         self.camera.position = camera_pos_asl;
         self.camera.forward = camera_fwd_lv95;
-        self.camera.up = camera_up_lv95;
-        self.camera_uniform.update(&self.camera);
-        self.queue.write_buffer(
-            &self.camera_buffer,
-            0,
-            bytemuck::cast_slice(&[self.camera_uniform]),
+        self.camera.up = request.camera_up;
+        self.camera.intrinsics = request
+            .intrinsics
+            .unwrap_or_else(|| self.intrinsics.clone());
+        let intrinsics = self.camera.intrinsics.clone();
+        let image_mask = self.mask(&intrinsics);
+        let size = self.target_size(&intrinsics, self.outputs)?;
+        let target = &self.targets[&size];
+        let (frame, row_poses, band_rotations) = match request.rolling_shutter {
+            Some(rolling_shutter) => {
+                let bands = rolling_shutter.bands(&self.camera);
+                let band_rotations: Vec<_> = bands
+                    .iter()
+                    .map(|(camera, rows)| (camera.rotation(), rows.clone()))
+                    .collect();
+                let bands: Vec<_> = bands
+                    .iter()
+                    .map(|(camera, rows)| (camera, rows.clone()))
+                    .collect();
+                let frame = self
+                    .draw_bands(&bands, target, terrain, &depth_mapping, self.outputs)
+                    .await?;
+                (
+                    frame,
+                    Some(rolling_shutter.row_poses(&self.camera)),
+                    band_rotations,
+                )
+            }
+            None => {
+                let rows = 0..target.size.height;
+                let bands = [(&self.camera, rows.clone())];
+                let frame = self
+                    .draw_bands(&bands, target, terrain, &depth_mapping, self.outputs)
+                    .await?;
+                (frame, None, vec![(self.camera.rotation(), rows)])
+            }
+        };
+        let image_rgba = frame.rgba;
+        let image_world = frame.world.map(|offsets_m| WorldMap {
+            origin: terrain.origin,
+            offsets_m,
+        });
+        let image_normals = frame
+            .normals
+            .map(|normals| NormalMap::new(normals, &band_rotations, size.0));
+        let image_labels = frame.labels;
+        let image_object_height = frame.object_height;
+        let image_depth = self.with_depth_semantics(&intrinsics, frame.depth);
+
+        if let Some((panorama_camera, _)) = &mut self.panorama {
+            let mut heading = Vector3::new(camera_fwd_lv95.x, camera_fwd_lv95.y, 0.0);
+            if heading.norm() < 1e-3 {
+                heading = Vector3::new(0.0, 1.0, 0.0);
+            }
+            panorama_camera.position = camera_pos_asl;
+            panorama_camera.forward = heading.normalize();
+            panorama_camera.up = Vector3::new(0.0, 0.0, 1.0);
+        }
+        let (panorama_rgba, panorama_depth) = match &self.panorama {
+            Some((panorama_camera, panorama_target)) => {
+                let (rgba, depth) = self
+                    .draw(panorama_camera, panorama_target, terrain, &depth_mapping)
+                    .await?;
+                let panorama_intrinsics = panorama_camera.intrinsics.clone();
+                (
+                    Some(rgba),
+                    Some(self.with_depth_semantics(&panorama_intrinsics, depth)),
+                )
+            }
+            None => (None, None),
+        };
+
+        let cubemap = match &self.cubemap {
+            Some(cubemap_target) => {
+                let face_size_px = cubemap_target.size.width;
+                let mut cubemap = Cubemap {
+                    position: camera_pos_asl,
+                    face_size_px,
+                    ..Default::default()
+                };
+                for face in 0..FACE_DIRECTIONS.len() {
+                    let face_camera = Cubemap::face_camera(camera_pos_asl, face, face_size_px);
+                    let (rgba, depth) = self
+                        .draw(&face_camera, cubemap_target, terrain, &depth_mapping)
+                        .await?;
+                    cubemap.faces_rgba.push(rgba);
+                    cubemap.faces_depth.push(depth);
+                }
+                Some(cubemap)
+            }
+            None => None,
+        };
+
+        let (rig_images, rig_disparities) = match request.rig.as_deref() {
+            Some(rig) => {
+                let (images, disparities) = self.draw_rig(rig, terrain, &depth_mapping).await?;
+                (Some(images), Some(disparities))
+            }
+            None => (None, None),
+        };
+
+        Ok(RenderedRequest {
+            camera_pos_agl: request.camera_pos_agl,
+            camera_pos_lv95: camera_pos_asl,
+            camera_forward: self.camera.forward,
+            camera_up: self.camera.up,
+            request_id: request.request_id,
+            image_rgba,
+            image_depth,
+            depth_mapping,
+            image_mask,
+            image_world,
+            image_normals,
+            image_labels,
+            image_object_height,
+            panorama_rgba,
+            panorama_depth,
+            cubemap,
+            row_poses,
+            body_pose: request.body_pose,
+            rig_images,
+            rig_disparities,
+        })
+    }
+
+    /// Render all cameras of a rig mounted at the current camera pose
+    ///
+    /// Returns the image of each camera and the disparity map of each stereo pair.
+    async fn draw_rig(
+        &mut self,
+        rig: &Rig,
+        terrain: &TerrainModels,
+        depth_mapping: &DepthMapping,
+    ) -> Result<(Vec<RigImage>, Vec<Vec<f32>>)> {
+        let cameras: Vec<Camera> = rig
+            .cameras
+            .iter()
+            .map(|rig_camera| rig_camera.camera(&self.camera))
+            .collect();
+        let mut images = Vec::new();
+        for camera in &cameras {
+            let size = self.target_size(&camera.intrinsics, Outputs::default())?;
+            let (image_rgba, image_depth) = self
+                .draw(camera, &self.targets[&size], terrain, depth_mapping)
+                .await?;
+            images.push(RigImage {
+                camera_pos_lv95: camera.position,
+                camera_forward: camera.forward,
+                camera_up: camera.up,
+                image_rgba,
+                image_depth,
+            });
+        }
+
+        let disparities = rig
+            .stereo_pairs
+            .iter()
+            .map(|pair| rig.disparity(pair, &cameras[pair.left], &images[pair.left].image_depth))
+            .collect();
+        for (image, camera) in images.iter_mut().zip(&cameras) {
+            let depth = std::mem::take(&mut image.image_depth);
+            image.image_depth = self.with_depth_semantics(&camera.intrinsics, depth);
+        }
+        Ok((images, disparities))
+    }
+
+    /// Render a north up orthophoto and surface model of an LV95 bounding box straight from above
+    ///
+    /// The box is given by its south west and north east corners. It is extended towards the
+    /// south and east to a whole number of pixels.
+    pub async fn render_orthophoto(
+        &self,
+        min_lv95: Point2<f64>,
+        max_lv95: Point2<f64>,
+        gsd_m: f32,
+        storage_config: &StorageConfig,
+    ) -> Result<Orthophoto> {
+        ensure!(gsd_m > 0.0, "Ground sampling distance has to be positive");
+        let width_px = ((max_lv95.x - min_lv95.x) / gsd_m as f64).ceil() as u32;
+        let height_px = ((max_lv95.y - min_lv95.y) / gsd_m as f64).ceil() as u32;
+        ensure!(
+            (1..=16384).contains(&width_px) && (1..=16384).contains(&height_px),
+            "Orthophoto size of {}x{} pixels is not supported",
+            width_px,
+            height_px
+        );
+        // The image is rendered wider to align its rows and cropped to the box afterwards
+        let render_width_px = width_px.div_ceil(WIDTH_ALIGNMENT_PX) * WIDTH_ALIGNMENT_PX;
+        let origin = Coords::new(min_lv95.x, max_lv95.y, 0.0);
+        let size_m = Vector3::new(
+            render_width_px as f32 * gsd_m,
+            height_px as f32 * gsd_m,
+            0.0,
+        );
+        let center = Coords::new(
+            origin.x + 0.5 * size_m.x as f64,
+            origin.y - 0.5 * size_m.y as f64,
+            ORTHOPHOTO_CAMERA_ALTITUDE_M as f64,
+        );
+
+        let mut camera = Camera::new(
+            center,
+            Intrinsics::orthographic(gsd_m, render_width_px, height_px),
+        );
+        camera.up = Vector3::new(0.0, 1.0, 0.0);
+        let target =
+            RenderTarget::new(&self.device, render_width_px, height_px, Outputs::default());
+        info!(
+            "Rendering {}x{} orthophoto at {:?} with {}m GSD",
+            width_px, height_px, &center, gsd_m
         );
+        let terrain = TerrainGrid::new(
+            center.into(),
+            ORTHOPHOTO_CAMERA_ALTITUDE_M,
+            &[camera.intrinsics.clone()],
+            0.5 * size_m.norm(),
+            storage_config,
+            false,
+        )
+        .models(
+            &self.device,
+            &self.queue,
+            &self.texture_bind_group_layout,
+            false,
+        );
+        let (image_rgba, depth) = self
+            .draw(&camera, &target, &terrain, &DepthMapping::default())
+            .await?;
+
+        let elevation_m = depth
+            .chunks_exact(render_width_px as usize)
+            .flat_map(|row| &row[..width_px as usize])
+            .map(|distance_m| ORTHOPHOTO_CAMERA_ALTITUDE_M - distance_m)
+            .collect();
+        Ok(Orthophoto {
+            origin,
+            gsd_m,
+            image_rgba: image::imageops::crop_imm(&image_rgba, 0, 0, width_px, height_px)
+                .to_image(),
+            elevation_m,
+        })
+    }
+
+    /// Render the models from the given camera into a target and read back color and depth
+    ///
+    /// The depth is the metric range of each pixel, or the distance along the optical axis for
+    /// orthographic cameras, NaN for sky.
+    async fn draw(
+        &self,
+        camera: &Camera,
+        target: &RenderTarget,
+        terrain: &TerrainModels,
+        depth_mapping: &DepthMapping,
+    ) -> Result<(ImageBuffer<Rgba<u8>, Vec<u8>>, Vec<f32>)> {
+        let frame = self
+            .draw_bands(
+                &[(camera, 0..target.size.height)],
+                target,
+                terrain,
+                depth_mapping,
+                Outputs::default(),
+            )
+            .await?;
+        Ok((frame.rgba, frame.depth))
+    }
 
-        let render_pass_desc = wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &self.render_texture_view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
+    /// Render each band of rows from its own camera into a target and read back color, depth and
+    /// the requested additional outputs
+    async fn draw_bands(
+        &self,
+        bands: &[(&Camera, Range<u32>)],
+        target: &RenderTarget,
+        terrain: &TerrainModels,
+        depth_mapping: &DepthMapping,
+        outputs: Outputs,
+    ) -> Result<Frame> {
+        let world_target = Self::output_target(outputs.world, &target.world, "world")?;
+        let normal_target = Self::output_target(outputs.normals, &target.normal, "normal")?;
+        let label_target = Self::output_target(outputs.labels, &target.label, "label")?;
+        let object_height_target = Self::output_target(
+            outputs.object_height,
+            &target.object_height,
+            "object height",
+        )?;
+        let (render_pipeline, reverse_render_pipeline) = if outputs.in_color_pass() {
+            let [render_pipeline, reverse_render_pipeline] = self
+                .output_render_pipelines
+                .as_ref()
+                .context("Outputs are drawn before being enabled")?;
+            (render_pipeline, reverse_render_pipeline)
+        } else {
+            (&self.render_pipeline, &self.reverse_render_pipeline)
+        };
+
+        // Equirectangular cameras draw the triangles crossing the seam behind them in two more
+        // passes, with the longitudes on either side of the seam wrapped past it
+        let passes = bands.iter().flat_map(|(camera, rows)| {
+            let longitude_wraps: &[f32] =
+                if camera.intrinsics.model == ProjectionModel::Equirectangular {
+                    &[0.0, 2.0 * PI, -2.0 * PI]
+                } else {
+                    &[0.0]
+                };
+            longitude_wraps
+                .iter()
+                .map(move |longitude_wrap| (*camera, rows, *longitude_wrap))
+        });
+        for (pass, (camera, rows, longitude_wrap)) in passes.enumerate() {
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            let mut camera_uniform = CameraUniform::new();
+            camera_uniform.update(camera, terrain.origin);
+            camera_uniform.set_depth_mapping(depth_mapping);
+            if camera.intrinsics.model != ProjectionModel::Orthographic {
+                camera_uniform.set_earth_curvature(self.refraction_coefficient);
+            }
+            camera_uniform.set_longitude_wrap(longitude_wrap);
+            // The uniform is shared by all passes, so each pass needs its own submission
+            self.queue.write_buffer(
+                &self.camera_buffer,
+                0,
+                bytemuck::cast_slice(&[camera_uniform]),
+            );
+
+            // Only the first pass clears the target, the others draw on top of it
+            let (color_load, float_load, depth_load) = if pass == 0 {
+                (
+                    wgpu::LoadOp::Clear(wgpu::Color {
                         r: 0.1,
                         g: 0.2,
                         b: 0.3,
                         a: 1.0,
                     }),
-                    store: true,
+                    wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    wgpu::LoadOp::Clear(depth_mapping.sky_depth()),
+                )
+            } else {
+                (wgpu::LoadOp::Load, wgpu::LoadOp::Load, wgpu::LoadOp::Load)
+            };
+            // Attachments match the targets of the pipeline, the color one only without outputs
+            let [world_attachment, normal_attachment, object_height_attachment] =
+                [world_target, normal_target, object_height_target].map(|output_target| {
+                    output_target.map(|output_target| wgpu::RenderPassColorAttachment {
+                        view: &output_target.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: float_load,
+                            store: true,
+                        },
+                    })
+                });
+            let color_attachments = [
+                Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: color_load,
+                        store: true,
+                    },
+                }),
+                world_attachment,
+                normal_attachment,
+                object_height_attachment,
+            ];
+            let render_pass_desc = wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: if outputs.in_color_pass() {
+                    &color_attachments[..]
+                } else {
+                    &color_attachments[..1]
                 },
-            })],
-            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                view: &self.depth_texture.view,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: true,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &target.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: depth_load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
                 }),
-                stencil_ops: None,
-            }),
-        };
+            };
 
-        {
-            // Scope for render_pass
-            let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
-            render_pass.set_pipeline(&self.render_pipeline);
-            for model in models {
-                render_pass.draw_model(model, &self.camera_bind_group);
+            {
+                // Scope for render_pass
+                let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
+                render_pass.set_pipeline(if depth_mapping.is_reversed() {
+                    reverse_render_pipeline
+                } else {
+                    render_pipeline
+                });
+                render_pass.set_scissor_rect(0, rows.start, target.size.width, rows.len() as u32);
+                for model in &terrain.models {
+                    render_pass.draw_model(model, &self.camera_bind_group);
+                }
+            }
+            if let Some(label_target) = label_target {
+                // The label texture replaces the diffuse one, so the labels need a pass of their
+                // own, which only keeps the surfaces of the color pass by its depth
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Label Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &label_target.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: if pass == 0 {
+                                wgpu::LoadOp::Clear(wgpu::Color {
+                                    r: 1.0,
+                                    g: 0.0,
+                                    b: 0.0,
+                                    a: 1.0,
+                                })
+                            } else {
+                                wgpu::LoadOp::Load
+                            },
+                            store: true,
+                        },
+                    })],
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: &target.depth_texture.view,
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Load,
+                            store: true,
+                        }),
+                        stencil_ops: None,
+                    }),
+                });
+                render_pass.set_pipeline(if depth_mapping.is_reversed() {
+                    &self.reverse_label_pipeline
+                } else {
+                    &self.label_pipeline
+                });
+                render_pass.set_scissor_rect(0, rows.start, target.size.width, rows.len() as u32);
+                for model in &terrain.models {
+                    if let Some(material) = model.materials.get(LABEL_MATERIAL) {
+                        render_pass.draw_model_instanced_with_material(
+                            model,
+                            material,
+                            0..1,
+                            &self.camera_bind_group,
+                        );
+                    }
+                }
             }
+            self.queue.submit(Some(encoder.finish()));
         }
 
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
         let u32_size = std::mem::size_of::<u32>() as u32;
         let f32_size = std::mem::size_of::<f32>() as u32;
         encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
-                texture: &self.render_texture,
+                texture: &target.texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
             wgpu::ImageCopyBuffer {
-                buffer: &self.output_buffer,
+                buffer: &target.output_buffer,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: NonZeroU32::new(u32_size * self.render_texture_size.width),
-                    rows_per_image: NonZeroU32::new(self.render_texture_size.height),
+                    bytes_per_row: NonZeroU32::new(u32_size * target.size.width),
+                    rows_per_image: NonZeroU32::new(target.size.height),
                 },
             },
-            self.render_texture_size,
+            target.size,
         );
 
         encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
-                texture: &self.depth_texture.texture,
+                texture: &target.depth_texture.texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
             wgpu::ImageCopyBuffer {
-                buffer: &self.depth_output_buffer,
+                buffer: &target.depth_output_buffer,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: NonZeroU32::new(f32_size * self.render_texture_size.width),
-                    rows_per_image: NonZeroU32::new(self.render_texture_size.height),
+                    bytes_per_row: NonZeroU32::new(f32_size * target.size.width),
+                    rows_per_image: NonZeroU32::new(target.size.height),
                 },
             },
-            self.render_texture_size,
+            target.size,
         );
 
+        let output_targets = [
+            world_target,
+            normal_target,
+            label_target,
+            object_height_target,
+        ];
+        for output_target in output_targets.into_iter().flatten() {
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &output_target.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                wgpu::ImageCopyBuffer {
+                    buffer: &output_target.output_buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(
+                            output_target.pixel_size * target.size.width,
+                        ),
+                        rows_per_image: NonZeroU32::new(target.size.height),
+                    },
+                },
+                target.size,
+            );
+        }
+
         self.queue.submit(Some(encoder.finish()));
 
-        let rendered_request;
+        let image_rgba;
+        let image_depth: Vec<f32>;
 
         {
-            let buffer_slice = self.output_buffer.slice(..);
-            let depth_buffer_slice = self.depth_output_buffer.slice(..);
+            let buffer_slice = target.output_buffer.slice(..);
+            let depth_buffer_slice = target.depth_output_buffer.slice(..);
 
             let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
             buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
@@ -536,25 +1653,135 @@ impl Renderer {
             let data = (*buffer_slice.get_mapped_range()).to_vec();
             let depth_data = (*depth_buffer_slice.get_mapped_range()).to_vec();
 
-            let image_rgba = ImageBuffer::<Rgba<u8>, _>::from_raw(
-                self.render_texture_size.width,
-                self.render_texture_size.height,
-                data,
-            )
-            .unwrap();
+            image_rgba =
+                ImageBuffer::<Rgba<u8>, _>::from_raw(target.size.width, target.size.height, data)
+                    .unwrap();
+            image_depth = bytemuck::cast_slice(&depth_data)
+                .iter()
+                .map(|depth| depth_mapping.distance_m(*depth).unwrap_or(f32::NAN))
+                .collect();
+        }
+        target.output_buffer.unmap();
+        target.depth_output_buffer.unmap();
 
-            rendered_request = RenderedRequest {
-                camera_pos_agl,
-                camera_pos_lv95: camera_pos_asl,
-                camera_forward: self.camera.forward,
-                camera_up: self.camera.up,
-                request_id,
-                image_rgba,
-                image_depth: bytemuck::cast_slice(&depth_data).to_vec(),
-            };
+        let world = match world_target {
+            Some(world_target) => Some(self.read_float_target(world_target, &image_depth).await),
+            None => None,
+        };
+        let normals = match normal_target {
+            Some(normal_target) => Some(self.read_float_target(normal_target, &image_depth).await),
+            None => None,
+        };
+        let labels = match label_target {
+            Some(label_target) => Some(
+                self.read_label_target(label_target, target.size, &image_depth)
+                    .await,
+            ),
+            None => None,
+        };
+        let object_height = match object_height_target {
+            Some(object_height_target) => Some(
+                self.read_height_target(object_height_target, &image_depth)
+                    .await,
+            ),
+            None => None,
+        };
+        Ok(Frame {
+            rgba: image_rgba,
+            depth: image_depth,
+            world,
+            normals,
+            labels,
+            object_height,
+        })
+    }
+
+    /// Output target of an enabled output, which the target has to have been created with
+    fn output_target<'a>(
+        enabled: bool,
+        output_target: &'a Option<OutputTarget>,
+        name: &str,
+    ) -> Result<Option<&'a OutputTarget>> {
+        if !enabled {
+            return Ok(None);
         }
-        self.output_buffer.unmap();
-        self.depth_output_buffer.unmap();
-        Ok(rendered_request)
+        output_target
+            .as_ref()
+            .map(Some)
+            .with_context(|| format!("Target has no {} output", name))
+    }
+
+    /// Read back the first channel of the label target, LABEL_SKY where the depth is NaN
+    async fn read_label_target(
+        &self,
+        label_target: &OutputTarget,
+        size: wgpu::Extent3d,
+        depth: &[f32],
+    ) -> GrayImage {
+        let data = label_target.read(&self.device).await;
+        let labels = data
+            .chunks_exact(4)
+            .zip(depth)
+            .map(|(pixel, depth)| if depth.is_nan() { LABEL_SKY } else { pixel[0] })
+            .collect();
+        GrayImage::from_raw(size.width, size.height, labels).unwrap()
+    }
+
+    /// Read back a single channel float target, NaN where the depth is NaN
+    async fn read_height_target(&self, height_target: &OutputTarget, depth: &[f32]) -> Vec<f32> {
+        let data = height_target.read(&self.device).await;
+        let heights: &[f32] = bytemuck::cast_slice(&data);
+        heights
+            .iter()
+            .zip(depth)
+            .map(|(height, depth)| if depth.is_nan() { f32::NAN } else { *height })
+            .collect()
+    }
+
+    /// Read back the first three channels of a float target, NaN where the depth is NaN
+    async fn read_float_target(&self, float_target: &OutputTarget, depth: &[f32]) -> Vec<f32> {
+        let data = float_target.read(&self.device).await;
+        let pixels: &[[f32; 4]] = bytemuck::cast_slice(&data);
+        // The sky keeps the cleared value, mark it by its depth instead
+        pixels
+            .iter()
+            .zip(depth)
+            .flat_map(|(pixel, depth)| {
+                if depth.is_nan() {
+                    [f32::NAN; 3]
+                } else {
+                    [pixel[0], pixel[1], pixel[2]]
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_outputs_writes_rendered_outputs_only() {
+        let dir = std::env::temp_dir().join(format!("outputs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let request = RenderedRequest {
+            image_labels: Some(GrayImage::new(2, 1)),
+            image_object_height: Some(vec![1.0, f32::NAN]),
+            ..Default::default()
+        };
+        let paths = request.save_outputs(dir.join("image_7")).unwrap();
+        assert_eq!(paths.label_image_path, Some("image_7_labels.png".into()));
+        assert_eq!(
+            paths.object_height_image_path,
+            Some("image_7_object_height.bin".into())
+        );
+        assert!(paths.world_image_path.is_none());
+        assert!(paths.panorama_rgb_image_path.is_none());
+        let heights = std::fs::metadata(dir.join("image_7_object_height.bin")).unwrap();
+        assert_eq!(heights.len(), 8);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(request.save_outputs(dir.join("image_7")).is_err());
     }
 }
@@ -0,0 +1,263 @@
+use std::path::Path;
+
+use anyhow::{ensure, Result};
+use nalgebra::{Matrix3, Point2, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{Camera, Intrinsics, ProjectionModel};
+use crate::renderer::WIDTH_ALIGNMENT_PX;
+
+/// Allowed deviation from a rectified stereo geometry, in radians and relative baseline
+const RECTIFICATION_TOLERANCE: f32 = 1e-3;
+
+fn identity() -> [[f32; 3]; 3] {
+    Matrix3::identity().into()
+}
+
+/// A camera mounted on a rig
+///
+/// The body frame of the rig is the frame of the requested camera pose (x right, y down,
+/// z forward).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RigCamera {
+    /// Name used for the output files of this camera
+    pub name: String,
+    pub intrinsics: Intrinsics,
+    /// Rotation from the camera frame to the body frame, row major
+    #[serde(default = "identity")]
+    pub rotation_body_camera: [[f32; 3]; 3],
+    /// Position of the camera in the body frame in meters
+    #[serde(default)]
+    pub translation_body_camera_m: [f32; 3],
+}
+
+impl RigCamera {
+    fn rotation(&self) -> Matrix3<f32> {
+        // The nested arrays are rows, nalgebra converts them as columns
+        Matrix3::from(self.rotation_body_camera).transpose()
+    }
+
+    /// Camera of the rig given the pose of the body
+    pub fn camera(&self, body: &Camera) -> Camera {
+        let body_rotation = body.rotation();
+        let rotation = body_rotation * self.rotation();
+        let mut camera = Camera::new(
+            body.position + (body_rotation * Vector3::from(self.translation_body_camera_m)).cast(),
+            self.intrinsics.clone(),
+        );
+        camera.forward = rotation.column(2).clone_owned();
+        camera.up = -rotation.column(1).clone_owned();
+        camera
+    }
+}
+
+/// Two cameras of a rig forming a rectified stereo pair
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct StereoPair {
+    /// Index of the left camera, the disparity map is in its image
+    pub left: usize,
+    /// Index of the right camera
+    pub right: usize,
+}
+
+/// Several cameras rendered from the same pose
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Rig {
+    pub cameras: Vec<RigCamera>,
+    /// Rectified pairs to compute disparity maps for
+    #[serde(default)]
+    pub stereo_pairs: Vec<StereoPair>,
+}
+
+impl Rig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let rig: Rig = toml::from_str(&std::fs::read_to_string(path)?)?;
+        for camera in &rig.cameras {
+            ensure!(
+                camera
+                    .intrinsics
+                    .image_width_px
+                    .is_multiple_of(WIDTH_ALIGNMENT_PX),
+                "Width of rig camera {} has to be a multiple of {} px",
+                camera.name,
+                WIDTH_ALIGNMENT_PX
+            );
+        }
+        for pair in &rig.stereo_pairs {
+            rig.validate_stereo_pair(pair)?;
+        }
+        Ok(rig)
+    }
+
+    /// Ensure that a pair shares pinhole intrinsics and orientation and has a horizontal baseline
+    fn validate_stereo_pair(&self, pair: &StereoPair) -> Result<()> {
+        ensure!(
+            pair.left < self.cameras.len() && pair.right < self.cameras.len(),
+            "Stereo pair {:?} references a missing camera",
+            pair
+        );
+        let left = &self.cameras[pair.left];
+        let right = &self.cameras[pair.right];
+        ensure!(
+            left.intrinsics.model == ProjectionModel::Pinhole
+                && left.intrinsics.distortion.is_none(),
+            "Stereo pair {} - {} is not rectified, the cameras have to be undistorted pinholes",
+            left.name,
+            right.name
+        );
+        let (l, r) = (&left.intrinsics, &right.intrinsics);
+        ensure!(
+            l.model == r.model
+                && l.distortion == r.distortion
+                && l.focal_length_x_px == r.focal_length_x_px
+                && l.focal_length_y_px == r.focal_length_y_px
+                && l.optical_center_x_px == r.optical_center_x_px
+                && l.optical_center_y_px == r.optical_center_y_px
+                && l.image_width_px == r.image_width_px
+                && l.image_height_px == r.image_height_px,
+            "Stereo pair {} - {} is not rectified, the intrinsics differ",
+            left.name,
+            right.name
+        );
+        ensure!(
+            (left.rotation().transpose() * right.rotation() - Matrix3::identity()).amax()
+                < RECTIFICATION_TOLERANCE,
+            "Stereo pair {} - {} is not rectified, the cameras are rotated",
+            left.name,
+            right.name
+        );
+        let baseline = self.baseline_m(pair);
+        ensure!(
+            baseline.x > 0.0 && baseline.yz().norm() < RECTIFICATION_TOLERANCE * baseline.x,
+            "Stereo pair {} - {} is not rectified, the right camera has to be offset along x",
+            left.name,
+            right.name
+        );
+        Ok(())
+    }
+
+    /// Position of the right camera in the frame of the left camera
+    fn baseline_m(&self, pair: &StereoPair) -> Vector3<f32> {
+        let left = &self.cameras[pair.left];
+        let right = &self.cameras[pair.right];
+        left.rotation().transpose()
+            * (Vector3::from(right.translation_body_camera_m)
+                - Vector3::from(left.translation_body_camera_m))
+    }
+
+    /// Disparity in pixels of each pixel of the left image, NaN where nothing was rendered
+    ///
+    /// # Arguments
+    ///
+    /// * `left_camera` - The left camera as rendered, see RigCamera::camera
+    /// * `left_distance_m` - Distance along the ray of each pixel of the left image, NaN for sky
+    pub fn disparity(
+        &self,
+        pair: &StereoPair,
+        left_camera: &Camera,
+        left_distance_m: &[f32],
+    ) -> Vec<f32> {
+        let baseline_m = self.baseline_m(pair).x;
+        let intrinsics = &left_camera.intrinsics;
+        let width = intrinsics.image_width_px as usize;
+        left_distance_m
+            .iter()
+            .enumerate()
+            .map(|(idx, distance_m)| {
+                let pixel = Point2::new((idx % width) as f32 + 0.5, (idx / width) as f32 + 0.5);
+                // The ray has unit z, so the depth along the optical axis is distance over its norm
+                match left_camera.unproject(pixel, 1.0) {
+                    Ok(ray) => {
+                        intrinsics.focal_length_x_px * baseline_m * ray.coords.norm() / distance_m
+                    }
+                    Err(_) => f32::NAN,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+    use crate::Coords;
+
+    fn pinhole() -> Intrinsics {
+        Intrinsics {
+            model: ProjectionModel::Pinhole,
+            distortion: None,
+            focal_length_x_px: 100.0,
+            focal_length_y_px: 100.0,
+            optical_center_x_px: 32.0,
+            optical_center_y_px: 24.0,
+            image_width_px: 64,
+            image_height_px: 48,
+        }
+    }
+
+    /// Pair looking to the right of the body with the given baseline
+    fn stereo_rig(baseline_m: f32) -> Rig {
+        // Camera x is the body z, camera z the body -x
+        let rotation_body_camera = [[0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]];
+        let camera = |name: &str, translation_body_camera_m| RigCamera {
+            name: name.to_string(),
+            intrinsics: pinhole(),
+            rotation_body_camera,
+            translation_body_camera_m,
+        };
+        Rig {
+            cameras: vec![
+                camera("left", [1.0, 0.0, 0.0]),
+                camera("right", [1.0, 0.0, baseline_m]),
+            ],
+            stereo_pairs: vec![StereoPair { left: 0, right: 1 }],
+        }
+    }
+
+    #[test]
+    fn disparity_of_a_fronto_parallel_plane() {
+        let rig = stereo_rig(0.5);
+        let pair = rig.stereo_pairs[0];
+        rig.validate_stereo_pair(&pair).unwrap();
+        let body = Camera::new(Coords::new(2_600_000.0, 1_200_000.0, 500.0), pinhole());
+        let left_camera = rig.cameras[pair.left].camera(&body);
+
+        // Plane 10 m in front of the left camera, with sky in the first pixel
+        let mut distance_m: Vec<f32> = (0..64 * 48)
+            .map(|idx| {
+                let pixel = Point2::new((idx % 64) as f32 + 0.5, (idx / 64) as f32 + 0.5);
+                left_camera.unproject(pixel, 10.0).unwrap().coords.norm()
+            })
+            .collect();
+        distance_m[0] = f32::NAN;
+
+        let disparity = rig.disparity(&pair, &left_camera, &distance_m);
+        assert!(disparity[0].is_nan());
+        // focal length times baseline over depth
+        for value in &disparity[1..] {
+            assert!((value - 5.0).abs() < 1e-4, "{}", value);
+        }
+
+        // It is the offset of a point between the left and the right image
+        let point_m = Point3::new(1.0, -0.5, 10.0);
+        let right_point_m = point_m - Vector3::new(0.5, 0.0, 0.0);
+        let offset_px = left_camera.project(point_m).x - left_camera.project(right_point_m).x;
+        assert!((offset_px - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn unrectified_pairs_are_rejected() {
+        let mut rig = stereo_rig(0.5);
+        rig.cameras[1].translation_body_camera_m = [1.0, 0.5, 0.0];
+        assert!(rig.validate_stereo_pair(&rig.stereo_pairs[0]).is_err());
+
+        let rig = stereo_rig(-0.5);
+        assert!(rig.validate_stereo_pair(&rig.stereo_pairs[0]).is_err());
+
+        let mut rig = stereo_rig(0.5);
+        rig.cameras[1].intrinsics.focal_length_x_px = 101.0;
+        assert!(rig.validate_stereo_pair(&rig.stereo_pairs[0]).is_err());
+    }
+}
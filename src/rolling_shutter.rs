@@ -0,0 +1,125 @@
+use std::ops::Range;
+
+use nalgebra::{Rotation3, Vector3};
+
+use crate::camera::Camera;
+use crate::Coords;
+
+/// Constant velocity motion of a camera during the row by row readout of a rolling shutter
+#[derive(Debug, Copy, Clone)]
+pub struct RollingShutter {
+    /// Time between the readout of two consecutive rows in seconds
+    pub line_readout_s: f32,
+    /// Linear velocity of the camera in LV95 in m/s
+    pub velocity_mps: Vector3<f32>,
+    /// Angular velocity in the camera frame (x right, y down, z forward) in rad/s
+    pub angular_velocity_radps: Vector3<f32>,
+    /// Number of consecutive rows rendered with the same pose, 1 renders every row separately
+    pub rows_per_band: u32,
+}
+
+/// Readout time of a row of a rolling shutter image and the pose it was rendered with
+///
+/// The pose is the one of the band of the row, evaluated at the readout of its center row.
+#[derive(Debug, Copy, Clone)]
+pub struct RowPose {
+    /// Time since the readout of the first row started
+    pub time_s: f32,
+    /// Time of the pose of the band
+    pub band_time_s: f32,
+    pub position: Coords,
+    pub forward: Vector3<f32>,
+    pub up: Vector3<f32>,
+}
+
+impl RollingShutter {
+    /// Camera moved from the pose at the start of the readout by the given time
+    pub fn camera_at(&self, start: &Camera, time_s: f32) -> Camera {
+        let rotation =
+            start.rotation() * Rotation3::new(self.angular_velocity_radps * time_s).matrix();
+        let mut camera = Camera::new(
+            start.position + (self.velocity_mps * time_s).cast(),
+            start.intrinsics.clone(),
+        );
+        camera.forward = rotation.column(2).clone_owned();
+        camera.up = -rotation.column(1).clone_owned();
+        camera
+    }
+
+    /// Readout time of the rows rendered together with the given row of an image of the given
+    /// height
+    ///
+    /// A band is rendered at the time its center row is read out, the last band may be shorter.
+    fn band_time_s(&self, row: u32, height: u32) -> f32 {
+        let rows_per_band = self.rows_per_band.max(1);
+        let band_start = row / rows_per_band * rows_per_band;
+        let band_end = (band_start + rows_per_band).min(height);
+        0.5 * (band_start + band_end - 1) as f32 * self.line_readout_s
+    }
+
+    /// Cameras of all bands of rows, together with the rows they cover
+    pub fn bands(&self, start: &Camera) -> Vec<(Camera, Range<u32>)> {
+        let height = start.intrinsics.image_height_px;
+        (0..height)
+            .step_by(self.rows_per_band.max(1) as usize)
+            .map(|row| {
+                let rows = row..(row + self.rows_per_band.max(1)).min(height);
+                (self.camera_at(start, self.band_time_s(row, height)), rows)
+            })
+            .collect()
+    }
+
+    /// Pose each row of the image was rendered with
+    pub fn row_poses(&self, start: &Camera) -> Vec<RowPose> {
+        let height = start.intrinsics.image_height_px;
+        (0..height)
+            .map(|row| {
+                let band_time_s = self.band_time_s(row, height);
+                let camera = self.camera_at(start, band_time_s);
+                RowPose {
+                    time_s: row as f32 * self.line_readout_s,
+                    band_time_s,
+                    position: camera.position,
+                    forward: camera.forward,
+                    up: camera.up,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Intrinsics;
+
+    #[test]
+    fn rows_share_the_pose_of_their_band() {
+        let rolling_shutter = RollingShutter {
+            line_readout_s: 1e-3,
+            velocity_mps: Vector3::new(10.0, 0.0, 0.0),
+            angular_velocity_radps: Vector3::zeros(),
+            rows_per_band: 4,
+        };
+        let start = Camera::new(
+            Coords::new(2_600_000.0, 1_200_000.0, 500.0),
+            Intrinsics::orthographic(1.0, 8, 10),
+        );
+        let bands = rolling_shutter.bands(&start);
+        let band_rows: Vec<_> = bands.iter().map(|(_, rows)| rows.clone()).collect();
+        assert_eq!(band_rows, vec![0..4, 4..8, 8..10]);
+
+        let row_poses = rolling_shutter.row_poses(&start);
+        assert_eq!(row_poses.len(), 10);
+        for (row, pose) in row_poses.iter().enumerate() {
+            assert!((pose.time_s - row as f32 * 1e-3).abs() < 1e-9);
+            let (band_camera, _) = &bands[row / 4];
+            assert_eq!(pose.position, band_camera.position);
+        }
+        // The first band is rendered at the readout of its center, 1.5 rows in
+        assert!((row_poses[0].band_time_s - 1.5e-3).abs() < 1e-9);
+        assert!((row_poses[0].position.x - (2_600_000.0 + 0.015)).abs() < 1e-6);
+        // The last band only covers rows 8 and 9
+        assert!((row_poses[9].band_time_s - 8.5e-3).abs() < 1e-9);
+    }
+}
@@ -1,12 +1,39 @@
 // Vertex shader
 
+// Projection models, have to match ProjectionModel::shader_id
+let MODEL_UNIFIED: u32 = 0u;
+let MODEL_PINHOLE: u32 = 1u;
+let MODEL_KANNALA_BRANDT: u32 = 2u;
+let MODEL_DOUBLE_SPHERE: u32 = 3u;
+let MODEL_EQUIRECTANGULAR: u32 = 4u;
+let MODEL_ORTHOGRAPHIC: u32 = 5u;
+
+// Depth modes, have to match DepthMode::shader_id
+let DEPTH_LINEAR: u32 = 0u;
+let DEPTH_REVERSE_Z: u32 = 1u;
+let DEPTH_LOGARITHMIC: u32 = 2u;
+
 struct Camera {
     view: mat4x4<f32>,
-    xi: f32,
+    // Model specific parameters, see ProjectionModel::shader_params
+    params: vec4<f32>,
+    // Distortion coefficients k1, k2, p1, p2
+    distortion: vec4<f32>,
+    model: u32,
     fx: f32,
     fy: f32,
     cx: f32,
     cy: f32,
+    k3: f32,
+    // Drop of the terrain per squared meter of horizontal distance, 0 renders a flat earth
+    earth_curvature: f32,
+    // Longitude added past the seam of equirectangular cameras, see longitude
+    longitude_wrap: f32,
+    position: vec4<f32>,
+    // Distance to depth buffer mapping, see DepthMapping
+    depth_mode: u32,
+    near: f32,
+    far: f32,
 }
 
 @group(1) @binding(0)
@@ -15,42 +42,228 @@ var<uniform> camera: Camera;
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) object_height: f32,
 }
 struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
+    // Invariant so the label pass reproduces the depth of the color pass exactly
+    @builtin(position) @invariant clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    // Position in view space
+    @location(1) view_pos: vec3<f32>,
+    // Longitude of the vertex, interpolated linearly in screen space
+    @location(2) @interpolate(linear) longitude: f32,
+    // Position of the unbent terrain relative to the terrain origin
+    @location(3) world_pos: vec3<f32>,
+    // Normal of the unbent terrain in LV95 axes
+    @location(4) normal: vec3<f32>,
+    // Height of the surface above the bare terrain
+    @location(5) object_height: f32,
+}
+
+// Normalized image coordinates of a view space point under the Kannala-Brandt model
+fn project_kannala_brandt(pos: vec3<f32>) -> vec2<f32> {
+    let r = length(pos.xy);
+    if (r <= 0.0) {
+        return vec2<f32>(0.0, 0.0);
+    }
+    let theta = atan2(r, -pos.z);
+    let theta2 = theta * theta;
+    let k = camera.params;
+    let theta_d = theta * (1.0 + theta2 * (k[0] + theta2 * (k[1] + theta2 * (k[2] + theta2 * k[3]))));
+    return pos.xy * (theta_d / r);
+}
+
+// Longitude of a view space point, the points on the far side of the seam are moved by
+// camera.longitude_wrap so the triangles crossing it stay in one piece
+fn longitude(pos: vec3<f32>) -> f32 {
+    let longitude = atan2(pos.x, -pos.z);
+    if (camera.longitude_wrap * longitude < 0.0) {
+        return longitude + camera.longitude_wrap;
+    }
+    return longitude;
+}
+
+// Brown-Conrady distortion of normalized image coordinates
+fn distort(view_pos: vec2<f32>) -> vec2<f32> {
+    // Coefficients follow the OpenCV convention with y pointing down
+    let pos = vec2<f32>(view_pos.x, -view_pos.y);
+    let k1 = camera.distortion[0];
+    let k2 = camera.distortion[1];
+    let p1 = camera.distortion[2];
+    let p2 = camera.distortion[3];
+    let r2 = dot(pos, pos);
+    let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * camera.k3));
+    let xy = pos.x * pos.y;
+    let distorted = vec2<f32>(
+        pos.x * radial + 2.0 * p1 * xy + p2 * (r2 + 2.0 * pos.x * pos.x),
+        pos.y * radial + p1 * (r2 + 2.0 * pos.y * pos.y) + 2.0 * p2 * xy,
+    );
+    return vec2<f32>(distorted.x, -distorted.y);
+}
+
+// Depth buffer value of a distance from the camera, has to match DepthMapping::depth
+fn map_depth(dist: f32) -> f32 {
+    if (camera.depth_mode == DEPTH_REVERSE_Z) {
+        return camera.near * (camera.far - dist) / (dist * (camera.far - camera.near));
+    } else if (camera.depth_mode == DEPTH_LOGARITHMIC) {
+        return log(dist / camera.near) / log(camera.far / camera.near);
+    }
+    return dist / camera.far;
 }
 
 @vertex
 fn vs_main(
     model: VertexInput,
 ) -> VertexOutput {
-    let world_position = vec4<f32>(model.position, 1.0);
+    var world_position = vec4<f32>(model.position, 1.0);
+    let offset = model.position.xy - camera.position.xy;
+    world_position[2] = world_position[2] - camera.earth_curvature * dot(offset, offset);
 
     var out: VertexOutput;
     let localPos: vec4<f32> = camera.view * world_position;
     let dist : f32 = sqrt((localPos[0]*localPos[0] + localPos[1]*localPos[1] + localPos[2]*localPos[2])/(localPos[3]*localPos[3]));
-    let norm : f32 = -localPos[2] + camera.xi * dist;
-    out.clip_position[0] = camera.fx * localPos[0] + camera.cx * norm;
-    out.clip_position[1] = camera.fy * localPos[1] + camera.cy * norm;
-    out.clip_position[2] = dist*norm *0.0001; // Simple distance, for the proper culling use (-localPos[2] * camera.xi + dist)*norm*0.0001
+    // Image coordinates are image_pos / norm
+    var image_pos : vec2<f32> = localPos.xy;
+    var norm : f32 = -localPos[2];
+    if (camera.model == MODEL_UNIFIED) {
+        norm = norm + camera.params[0] * dist;
+    } else if (camera.model == MODEL_KANNALA_BRANDT) {
+        norm = dist;
+        image_pos = project_kannala_brandt(localPos.xyz / localPos[3]) * dist;
+    } else if (camera.model == MODEL_DOUBLE_SPHERE) {
+        let z : f32 = camera.params[0] * dist - localPos[2] / localPos[3];
+        let d2 : f32 = sqrt(dot(localPos.xy, localPos.xy) / (localPos[3]*localPos[3]) + z * z);
+        norm = camera.params[1] * d2 + (1.0 - camera.params[1]) * z;
+    } else if (camera.model == MODEL_EQUIRECTANGULAR) {
+        norm = dist;
+        image_pos = vec2<f32>(longitude(localPos.xyz), asin(localPos[1] / localPos[3] / dist)) * dist;
+    } else if (camera.model == MODEL_ORTHOGRAPHIC) {
+        norm = 1.0;
+        image_pos = localPos.xy / localPos[3];
+    }
+    if (norm > 0.0) {
+        image_pos = distort(image_pos / norm) * norm;
+    }
+    out.clip_position[0] = camera.fx * image_pos[0] + camera.cx * norm;
+    out.clip_position[1] = camera.fy * image_pos[1] + camera.cy * norm;
+    // Only exact at the vertices for the nonlinear depth modes, see fragment_depth
+    out.clip_position[2] = map_depth(dist) * norm; // Simple distance, for the proper culling use (-localPos[2] * camera.xi + dist)
+    if (camera.model == MODEL_ORTHOGRAPHIC) {
+        // Distance along the optical axis, so depth maps directly to elevation for nadir views
+        out.clip_position[2] = map_depth(-localPos[2] / localPos[3]);
+    }
     out.clip_position[3] = norm;
 
     out.tex_coords = model.tex_coords;
+    out.view_pos = localPos.xyz / localPos[3];
+    out.longitude = longitude(localPos.xyz);
+    out.world_pos = model.position;
+    out.normal = model.normal;
+    out.object_height = model.object_height;
     return out;
 }
 
 
 // Fragment shader
 
+// Whether to drop a fragment of an equirectangular camera at the seam behind the camera. Triangles
+// crossing the seam are stretched over the whole panorama, their interpolated longitude does not
+// match the surface they cover. The passes with a longitude wrap draw them in one piece instead,
+// and only keep the half behind the camera.
+fn discard_at_seam(in: VertexOutput) -> bool {
+    if (camera.model != MODEL_EQUIRECTANGULAR) {
+        return false;
+    }
+    if (camera.longitude_wrap != 0.0 && abs(atan2(in.view_pos[0], -in.view_pos[2])) < 1.5707964) {
+        return true;
+    }
+    return abs(longitude(in.view_pos) - in.longitude) > 1.0;
+}
+
 @group(0) @binding(0)
 var t_diffuse: texture_2d<f32>;
 @group(0)@binding(1)
 var s_diffuse: sampler;
 
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // Read back as WorldMap
+    @location(1) world_pos: vec4<f32>,
+    // Read back as NormalMap
+    @location(2) normal: vec4<f32>,
+    // Read back as the object height of RenderedRequest
+    @location(3) object_height: f32,
+    @builtin(frag_depth) depth: f32,
+}
+
+// Color pass without additional outputs
+struct ColorOutput {
+    @location(0) color: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+}
+
+struct LabelOutput {
+    @location(0) label: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+}
+
+// Depth buffer value of a fragment. The reverse-Z and logarithmic depths do not interpolate
+// linearly inside the triangles, so they are computed from the distance of each fragment.
+fn fragment_depth(in: VertexOutput) -> f32 {
+    if (camera.depth_mode == DEPTH_LINEAR) {
+        return in.clip_position[2];
+    }
+    if (camera.model == MODEL_ORTHOGRAPHIC) {
+        return map_depth(-in.view_pos[2]);
+    }
+    return map_depth(length(in.view_pos));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+
+    if (discard_at_seam(in)) {
+        discard;
+    }
+
+    var out: FragmentOutput;
+    out.color = object_color;
+    out.world_pos = vec4<f32>(in.world_pos, 1.0);
+    out.normal = vec4<f32>(normalize(in.normal), 0.0);
+    out.object_height = in.object_height;
+    out.depth = fragment_depth(in);
+    return out;
+}
+
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+fn fs_color(in: VertexOutput) -> ColorOutput {
     let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
 
-    return object_color;
-}
\ No newline at end of file
+    if (discard_at_seam(in)) {
+        discard;
+    }
+
+    var out: ColorOutput;
+    out.color = object_color;
+    out.depth = fragment_depth(in);
+    return out;
+}
+
+// Class id of the land cover, drawn with the label material of each model on top of the depth of
+// the color pass, see landcover::LABEL_CLASSES
+@fragment
+fn fs_label(in: VertexOutput) -> LabelOutput {
+    // The label texture is sampled without interpolation, so this is the id divided by 255
+    let label: f32 = textureSample(t_diffuse, s_diffuse, in.tex_coords).r;
+
+    if (discard_at_seam(in)) {
+        discard;
+    }
+
+    var out: LabelOutput;
+    out.label = vec4<f32>(label, 0.0, 0.0, 1.0);
+    out.depth = fragment_depth(in);
+    return out;
+}
@@ -1,19 +1,59 @@
 use std::collections::HashMap;
 
 use log::{info, warn};
-use nalgebra::{distance, Point2, Point3};
+use nalgebra::{distance, Point3, Vector2};
 use rayon::iter::ParallelIterator;
 use rayon::prelude::*;
 
-use crate::camera::Camera;
+use crate::camera::{Camera, Intrinsics};
 use crate::config::StorageConfig;
 use crate::gridsquare::{GridCoords, GridSquare};
 use crate::model::Model;
+use crate::Coords;
 
 pub struct TerrainGrid {
+    center_coords: GridCoords,
     tiles: Vec<GridSquare>,
 }
 
+/// Models of a terrain grid with vertices relative to a common origin
+///
+/// Absolute LV95 coordinates are too large for the f32 vertex positions, so the vertices are
+/// stored relative to the corner of the central tile and cameras are placed relative to it.
+pub struct TerrainModels {
+    pub origin: Coords,
+    pub models: Vec<Model>,
+}
+
+/// Distance between the point (camera frame) and the points seen by the neighbouring pixels at
+/// the same depth, None if the point is not seen by the camera
+///
+/// Lens distortion is only calibrated within the image, outside of it the footprint without
+/// distortion is used.
+fn pixel_footprint_m(camera: &Camera, point_m: Point3<f32>) -> Option<f32> {
+    let intrinsics = &camera.intrinsics;
+    let point_px = camera.project(point_m);
+    let in_image = (0.0..=intrinsics.image_width_px as f32).contains(&point_px.x)
+        && (0.0..=intrinsics.image_height_px as f32).contains(&point_px.y);
+    if intrinsics.distortion.is_some() && !in_image {
+        let undistorted = Camera::new(
+            camera.position,
+            Intrinsics {
+                distortion: None,
+                ..intrinsics.clone()
+            },
+        );
+        return pixel_footprint_m(&undistorted, point_m);
+    }
+    let below_m = camera
+        .unproject(point_px + Vector2::new(0.0, 1.0), point_m.z)
+        .ok()?;
+    let right_m = camera
+        .unproject(point_px + Vector2::new(1.0, 0.0), point_m.z)
+        .ok()?;
+    Some(0.5 * (distance(&point_m, &below_m) + distance(&point_m, &right_m)))
+}
+
 impl TerrainGrid {
     /// Loads terrain in a circular grid around center_coords
     ///
@@ -21,32 +61,45 @@ impl TerrainGrid {
     ///
     /// * `center_coords` - GridSquare coordinates of the central tile
     /// * `agl_m` - Altitude of the viewpoint above the terrain
-    /// * `camera` - The camera used for the observation
+    /// * `intrinsics` - The cameras used for the observation, the terrain is loaded at the finest
+    ///   resolution any of them needs
     /// * `view_range_m` - The radius within which to load terrain, all tiles that are within this radius from any part of the central tile are loaded.
+    /// * `object_height` - Whether to also load the bare terrain for the height of the surface above it
     pub fn new(
         center_coords: GridCoords,
         agl_m: f32,
-        camera: &Camera,
+        intrinsics: &[Intrinsics],
         view_range_m: f32,
         storage_config: &StorageConfig,
+        object_height: bool,
     ) -> Self {
         let mut circle = center_coords.circle_m(view_range_m);
         circle.sort_by(|x, y| (y.0.x, y.0.y).cmp(&(x.0.x, x.0.y)));
         info!("Loading {} terrain tiles", circle.len());
+        let cameras: Vec<Camera> = intrinsics
+            .iter()
+            .map(|intrinsics| Camera::new(Coords::origin(), intrinsics.clone()))
+            .collect();
         let mut tiles: HashMap<GridCoords, GridSquare> = circle
             .par_iter()
             .filter_map(|coords| {
-                let pt1_m = Point3::new(0.0, coords.min_dist_m(&center_coords), agl_m);
-                let pt1_px = camera.project(pt1_m);
-                let pt2_m = camera
-                    .unproject(Point2::new(pt1_px.x, pt1_px.y + 1.0), agl_m)
-                    .unwrap();
-                let pt3_m = camera
-                    .unproject(Point2::new(pt1_px.x + 1.0, pt1_px.y), agl_m)
-                    .unwrap();
-                let resolution_m = 0.5 * (distance(&pt1_m, &pt2_m) + distance(&pt1_m, &pt3_m));
+                let point_m = Point3::new(0.0, coords.min_dist_m(&center_coords), agl_m);
+                // Tiles no camera sees a footprint for are loaded at the coarsest resolution
+                let resolution_m = cameras
+                    .iter()
+                    .filter_map(|camera| pixel_footprint_m(camera, point_m))
+                    .fold(f32::INFINITY, f32::min);
                 match GridSquare::new(*coords, 1f32 * resolution_m, storage_config.clone()) {
-                    Ok(square) => Some((*coords, square)),
+                    Ok(mut square) => {
+                        if object_height {
+                            if let Err(e) = square.load_object_height() {
+                                warn!("Unable to load object height at {:?}: {}", &coords, e);
+                                square.object_height =
+                                    Some(ndarray::Array2::zeros(square.elevation.dim()));
+                            }
+                        }
+                        Some((*coords, square))
+                    }
                     Err(e) => {
                         warn!("Unable to load square at {:?}: {}", &coords, e);
                         None
@@ -56,40 +109,48 @@ impl TerrainGrid {
             .collect();
 
         for coords in &circle {
-            let mut tile = tiles.remove(coords).unwrap();
-            tile.cleanup_borders(
-                tiles.get(&coords.below()),
-                tiles.get(&coords.right()),
-                None,
-                None,
-            );
-            tiles.insert(*coords, tile);
+            // Tiles that failed to load are missing
+            if let Some(mut tile) = tiles.remove(coords) {
+                tile.cleanup_borders(
+                    tiles.get(&coords.below()),
+                    tiles.get(&coords.right()),
+                    None,
+                    None,
+                );
+                tiles.insert(*coords, tile);
+            }
         }
         for coords in &circle {
-            let mut tile = tiles.remove(coords).unwrap();
-            tile.cleanup_borders(
-                None,
-                None,
-                tiles.get(&coords.above()),
-                tiles.get(&coords.left()),
-            );
-            tiles.insert(*coords, tile);
+            if let Some(mut tile) = tiles.remove(coords) {
+                tile.cleanup_borders(
+                    None,
+                    None,
+                    tiles.get(&coords.above()),
+                    tiles.get(&coords.left()),
+                );
+                tiles.insert(*coords, tile);
+            }
         }
         Self {
+            center_coords,
             tiles: tiles.into_values().into_iter().collect(),
         }
     }
 
+    /// Models of all tiles, with the land cover label material if labels is set
     pub fn models(
         &self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         texture_bind_group_layout: &wgpu::BindGroupLayout,
-    ) -> Vec<Model> {
-        self.tiles
+        labels: bool,
+    ) -> TerrainModels {
+        let origin: Coords = self.center_coords.into();
+        let models = self
+            .tiles
             .par_iter()
-            .filter_map(
-                |square| match square.model(device, queue, texture_bind_group_layout) {
+            .filter_map(|square| {
+                match square.model(device, queue, texture_bind_group_layout, origin, labels) {
                     Ok(square) => Some(square),
                     Err(e) => {
                         warn!(
@@ -98,8 +159,53 @@ impl TerrainGrid {
                         );
                         None
                     }
-                },
-            )
-            .collect()
+                }
+            })
+            .collect();
+        TerrainModels { origin, models }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Distortion, ProjectionModel};
+
+    #[test]
+    fn footprint_of_distant_points_falls_back_to_the_undistorted_camera() {
+        let intrinsics = Intrinsics {
+            model: ProjectionModel::Pinhole,
+            distortion: Some(Distortion {
+                k1: -0.28,
+                k2: 0.07,
+                ..Distortion::default()
+            }),
+            focal_length_x_px: 500.0,
+            focal_length_y_px: 500.0,
+            optical_center_x_px: 320.0,
+            optical_center_y_px: 240.0,
+            image_width_px: 640,
+            image_height_px: 480,
+        };
+        let camera = Camera::new(Coords::origin(), intrinsics.clone());
+        // On the optical axis a pixel covers depth over focal length
+        let footprint_m = pixel_footprint_m(&camera, Point3::new(0.0, 0.0, 100.0)).unwrap();
+        assert!((footprint_m - 0.2).abs() < 1e-3, "{}", footprint_m);
+
+        // A tile 20 km away seen from 100 m is far outside the calibrated image
+        let point_m = Point3::new(0.0, 20_000.0, 100.0);
+        let undistorted = Camera::new(
+            Coords::origin(),
+            Intrinsics {
+                distortion: None,
+                ..intrinsics
+            },
+        );
+        assert!(camera.project(point_m).y > 1e6);
+        assert_eq!(
+            pixel_footprint_m(&camera, point_m),
+            pixel_footprint_m(&undistorted, point_m)
+        );
+        assert!(pixel_footprint_m(&camera, point_m).is_some());
     }
 }
@@ -0,0 +1,28 @@
+use crate::Coords;
+
+/// LV95 position of the surface seen by each pixel of an image
+///
+/// The positions are f32 offsets from an origin close to the camera, which keeps them precise to
+/// millimeters within the view range although absolute LV95 coordinates need f64.
+#[derive(Debug, Clone, Default)]
+pub struct WorldMap {
+    pub origin: Coords,
+    /// Easting, northing and height minus the origin, of shape (height, width, 3) in row major
+    /// order, NaN where no terrain was rendered
+    pub offsets_m: Vec<f32>,
+}
+
+impl WorldMap {
+    /// Absolute LV95 position seen by the pixel with the given row major index, None for sky
+    pub fn position(&self, index: usize) -> Option<Coords> {
+        let offset = &self.offsets_m[3 * index..3 * index + 3];
+        if offset.iter().any(|value| value.is_nan()) {
+            return None;
+        }
+        Some(Coords::new(
+            self.origin.x + offset[0] as f64,
+            self.origin.y + offset[1] as f64,
+            self.origin.z + offset[2] as f64,
+        ))
+    }
+}